@@ -1,43 +1,45 @@
-use std::{fs, path::Path};
+use std::{fs, io, path::Path};
 
-use crate::{crypto::{self, password::{Password, PasswordError, PasswordPolicy}}, utils::{self, config::Config, config_utils, error::SnapError, gc::{GarbageCollector, GarbageLimit}, registry::BackupEntry, snapshot::Snapshot}};
+use crate::{crypto::{keyslot::KeyStore, password::PasswordPolicy}, utils::{self, chunker, config::Config, config_utils, error::SnapError, gc::{GarbageCollector, GarbageLimit}, journal::Journal, registry::BackupEntry, rules::BackupRules, snapshot::{FileEntry, Snapshot, MANIFEST_VERSION}}};
 
-pub fn backup_data(src: &Path, dest: &Path, comp: Option<String>, config: Option<Config>) -> Result<(), SnapError> {
+/// Back up `src` into a new snapshot at `dest`, skipping the files `rules` exclude.
+///
+/// With `dry_run` the backup is only simulated: the files that would be stored or reused and the old versions
+/// that would be pruned are reported, and nothing is written to `dest`, the registry or `gc.json`.
+pub fn backup_data(src: &Path, dest: &Path, comp: Option<String>, config: Option<Config>, rules: &BackupRules, dry_run: bool) -> Result<(), SnapError> {
     let password = utils::read_password()?;
 
     let mut registry = utils::get_registry();
     let entry = registry.find_entry(src.to_path_buf(), dest.to_path_buf());
 
-    let validated_password = if let Some(ent) = entry {
-        let prev_password = &ent.password;
-        let verification =  prev_password.verify(&password);
-        if let Err(err) =  verification {
-            return Err(SnapError::Password(err));
-        } 
-        else if let Ok(false) = verification {
-            return Err(SnapError::Password(PasswordError::IncorrectPassword));
-        }
-        prev_password
-    }else {
-        &Password::new(password.clone(), &PasswordPolicy::default())?
+    // a destination with a key store is bound to its passwords even when the registry lost track of it.
+    let (key, new_store) = if entry.is_some() || KeyStore::exists(dest) {
+        (utils::unlock_key(dest, &password, entry, !dry_run)?, None)
+    } else {
+        PasswordPolicy::default().validate(&password)?;
+        let master_key: [u8; 32] = rand::random();
+        (master_key, Some(KeyStore::new(&master_key, &password)))
     };
 
     let (algorithm, config) = confirm_algorithm(comp, config);
 
-    if !dest.exists() {
-        fs::create_dir_all(&dest)?;
-    }
-
     let blobs_dir = dest.join("blobs");
     let snapshot_dir = dest.join("snapshot");
 
-    fs::create_dir_all(&blobs_dir)?;
-    fs::create_dir_all(&snapshot_dir)?;
+    if !dry_run {
+        fs::create_dir_all(&blobs_dir)?;
+        fs::create_dir_all(&snapshot_dir)?;
+    }
 
     let mut garbage_info = GarbageLimit::from_json_to_gc()
                     .ok().unwrap_or_else(|| GarbageLimit::new());
 
-    let gc_limit = config.unwrap().general.gc_limit;
+    let general = config.unwrap().general;
+    let gc_limit = general.gc_limit;
+
+    // the chunk size comes from the config and is recorded with the other rules of the snapshot.
+    chunker::validate_chunk_size(general.chunk_size)?;
+    let rules = &BackupRules { chunk_size: general.chunk_size, ..rules.clone() };
 
     let gc_path = blobs_dir.to_string_lossy().to_string();
     let get_gc = garbage_info.get_gc_from_limit(gc_path);
@@ -50,16 +52,58 @@ pub fn backup_data(src: &Path, dest: &Path, comp: Option<String>, config: Option
 
 
     // we need the latest json file if there is any
-    let latest_json = 
-            utils::get_nth_recent_json_snapshot(0, &snapshot_dir)?
-                .map(|path| std::path::PathBuf::from(path));
+    let latest_json = if snapshot_dir.exists() {
+        utils::get_nth_recent_json_snapshot(0, &snapshot_dir)?.map(std::path::PathBuf::from)
+    } else {
+        None
+    };
 
-    let salt = utils::get_salt(&dest);
-    let key = crypto::derive_key(&password, &salt);
     let (engine, compression) = utils::generate_compression_engine(algorithm)?;
-    let snap = Snapshot::create(src, &blobs_dir, &key, latest_json.as_ref(), engine)?;
-    
-    let _ = snap.save(&snapshot_dir, &mut gc)?;
+
+    // blobs, the manifest, the key store of a new backup and the pruning of old versions
+    // only reach the destination together, when the journal commits.
+    let mut journal = if dry_run {
+        Journal::dry_run(dest, "backup")
+    } else {
+        Journal::begin(dest, "backup")?
+    };
+
+    let staged = Snapshot::create(src, &blobs_dir, &key, latest_json.as_ref(), engine, rules, &mut journal)
+        .and_then(|snap| {
+            let store = match &new_store {
+                Some(store) => Some(store.clone()),
+                None => KeyStore::load(dest)?,
+            };
+
+            // manifests of backups made before they were encrypted are encrypted once,
+            // along with the key store marking every manifest as encrypted.
+            if let Some(mut store) = store.filter(|store| store.manifest_version(&key) < MANIFEST_VERSION) {
+                Snapshot::encrypt_plaintext_manifests(&snapshot_dir, &key, &mut journal)?;
+
+                store.set_manifest_version(&key, MANIFEST_VERSION);
+                let json = serde_json::to_string_pretty(&store).map_err(io::Error::from)?;
+                journal.write(&KeyStore::path(dest), json.as_bytes())?;
+            }
+
+            snap.save(&snapshot_dir, &key, &mut gc, &mut journal)?;
+            Ok(snap)
+        });
+
+    let snap = match staged {
+        Ok(snap) => snap,
+        Err(err) => {
+            journal.rollback()?;
+            return Err(err);
+        }
+    };
+
+    if dry_run {
+        report_dry_run(&snap, &blobs_dir, &journal);
+        return Ok(());
+    }
+
+    journal.commit()?;
+
     let _ = garbage_info.add_garbage_collector_to_limit(gc);
     let _ = garbage_info.save();
 
@@ -73,7 +117,7 @@ pub fn backup_data(src: &Path, dest: &Path, comp: Option<String>, config: Option
         ent = en.clone();
     }
     else {
-        ent = BackupEntry::new(snap.timestamp, src.to_path_buf(), dest.to_path_buf(), &validated_password, compression);
+        ent = BackupEntry::new(snap.timestamp, src.to_path_buf(), dest.to_path_buf(), compression);
     }
 
     let _ = registry.add_backup(ent);
@@ -84,6 +128,45 @@ pub fn backup_data(src: &Path, dest: &Path, comp: Option<String>, config: Option
     Ok(())
 }
 
+/// Print what a backup would store, reuse and prune, from the snapshot it built and its dry run journal.
+fn report_dry_run(snapshot: &Snapshot, blobs_dir: &Path, journal: &Journal) {
+    let mut files: Vec<_> = snapshot.files.iter().filter(|(_, entry)| entry.has_blob()).collect();
+    files.sort_by(|a, b| a.0.cmp(b.0));
+
+    let (stored, reused): (Vec<_>, Vec<_>) = files.into_iter().partition(|(_, entry)| entry.isupdated);
+
+    let written_size = |entry: &FileEntry| -> u64 {
+        entry.blobs().iter().filter_map(|chunk| journal.written_size(&blobs_dir.join(&chunk.hash))).sum()
+    };
+
+    // chunks shared by several files of this backup are only written once.
+    let stored_size: u64 = journal.writes().iter()
+        .filter(|path| path.starts_with(blobs_dir))
+        .filter_map(|path| journal.written_size(path))
+        .sum();
+
+    println!("Dry run: nothing was written.");
+
+    println!("Would store {} files as new blobs ({stored_size} bytes compressed and encrypted):", stored.len());
+    for (path, entry) in &stored {
+        println!("  + {} ({} bytes)", path.display(), written_size(entry));
+    }
+
+    println!("Would reuse {} unchanged files:", reused.len());
+    for (path, _) in &reused {
+        println!("  = {}", path.display());
+    }
+
+    let pruned: Vec<_> = journal.removals().iter().filter(|path| path.starts_with(blobs_dir)).collect();
+
+    if !pruned.is_empty() {
+        println!("Would prune {} blobs of old versions:", pruned.len());
+        for path in pruned {
+            println!("  - {}", path.file_name().unwrap_or_default().to_string_lossy());
+        }
+    }
+}
+
 /// Given an algorithm and config, if the algorithm is `None` and the config is `None`, 
 /// build a global config and assign the compression on the config to the algorithm
 /// if only algorithm is `None`, assign the config's compression algorithm to `algorithm`
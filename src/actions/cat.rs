@@ -0,0 +1,50 @@
+use std::{io::{self, Write}, path::Path};
+
+use crate::utils::{self, error::SnapError, selector::SnapshotSelector, snapshot::Snapshot};
+
+/// Write the content of `file` as stored in the snapshot picked by `selector` at `origin` to stdout.
+///
+/// Only the blobs of that file are decrypted and decompressed, and streamed to stdout as they are.
+pub fn cat_file(selector: &SnapshotSelector, origin: &Path, file: &Path) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+
+    let registry = utils::get_registry();
+    let entry = registry.find_entry_from_dest(origin.to_path_buf());
+
+    let (algorithm, key) = if let Some(ent) = entry {
+        let key = utils::unlock_key(origin, &password, Some(ent), false)?;
+        (ent.compression_algorithm.clone(), key)
+    } else {
+        let message = "No backup available at path provided";
+        return Err(SnapError::Command(message.into()));
+    };
+
+    let engine = utils::generate_compression_engine(Some(algorithm))?.0;
+
+    let blobs_dir = origin.join("blobs");
+    let snapshot_path = match selector.resolve(&origin.join("snapshot"))? {
+        Some(path) => path,
+        None => return Err(SnapError::Command(format!("Snapshot {selector} does not exist."))),
+    };
+
+    let snapshot = Snapshot::from_json_to_snapshot(&snapshot_path, &key)?;
+    let file = utils::normalize_snapshot_path(file);
+
+    let file_entry = match snapshot.files.get(&file) {
+        Some(file_entry) if file_entry.has_blob() => file_entry,
+        Some(_) => {
+            let message = format!("{} is not a regular file.", file.display());
+            return Err(SnapError::Command(message));
+        },
+        None => {
+            let message = format!("{} is not part of the snapshot.", file.display());
+            return Err(SnapError::Command(message));
+        }
+    };
+
+    let mut stdout = io::stdout().lock();
+    file_entry.write_content(&blobs_dir, &key, engine.as_ref(), &mut stdout)?;
+    stdout.flush()?;
+
+    Ok(())
+}
@@ -1,28 +1,69 @@
-use std::{fs, path::{Path, PathBuf}};
+use std::{collections::{BTreeMap, HashSet}, io, path::{Path, PathBuf}};
 
-use crate::{crypto, utils::{self, error::SnapError, snapshot::Snapshot}};
+use crate::utils::{self, error::SnapError, gc::GarbageLimit, journal::Journal, selector::SnapshotSelector, snapshot::{FileEntry, Snapshot}};
 
-pub fn delete_data(nth: usize, target: &Path) -> Result<(), SnapError> {
+/// What deleting one snapshot removes from a backup.
+#[derive(Debug, PartialEq)]
+pub struct DeletePlan {
+    pub manifest: PathBuf,
+    /// blobs only the deleted snapshot references, by hash, with the first file using them.
+    pub removed_blobs: Vec<(String, PathBuf)>,
+    /// blobs other snapshots still reference, by hash, with the first file using them.
+    pub kept_blobs: Vec<(String, PathBuf)>,
+}
+
+impl DeletePlan {
+    /// Split the blobs of the snapshot with manifest `manifest` into the ones deleting it removes
+    /// and the ones the manifests in `others` still reference.
+    pub fn new(manifest: &Path, snapshot: &Snapshot, others: &[Snapshot]) -> Self {
+        let referenced: HashSet<String> = others.iter()
+            .flat_map(|other| other.files.values().flat_map(FileEntry::blobs).map(|chunk| chunk.hash))
+            .collect();
+
+        // one line per blob, files with identical chunks share it.
+        let mut blobs = BTreeMap::<String, &PathBuf>::new();
+        for (path, entry) in &snapshot.files {
+            for chunk in entry.blobs() {
+                blobs.entry(chunk.hash)
+                    .and_modify(|first| if path < *first { *first = path })
+                    .or_insert(path);
+            }
+        }
+
+        let (kept, removed): (Vec<_>, Vec<_>) = blobs.into_iter()
+            .map(|(hash, path)| (hash, path.clone()))
+            .partition(|(hash, _)| referenced.contains(hash));
+
+        Self { manifest: manifest.to_path_buf(), removed_blobs: removed, kept_blobs: kept }
+    }
+
+    /// Plan the deletion of `snapshot`, with manifest `manifest`, against every other manifest of `snapshot_dir`.
+    pub fn load(manifest: &Path, snapshot: &Snapshot, snapshot_dir: &Path, key: &[u8]) -> io::Result<Self> {
+        let others = utils::list_json_snapshots(snapshot_dir)?.into_iter()
+            .filter(|path| path != manifest)
+            .map(|path| Snapshot::from_json_to_snapshot(&path, key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self::new(manifest, snapshot, &others))
+    }
+}
+
+/// Delete the snapshot picked by `selector` from the backup at `target`, along with the blobs no other snapshot references.
+///
+/// With `dry_run` nothing is deleted: the manifest and blobs that would be removed, and the blobs that
+/// would be kept because other snapshots still reference them, are reported instead.
+pub fn delete_data(selector: &SnapshotSelector, target: &Path, dry_run: bool) -> Result<(), SnapError> {
     let password = utils::read_password()?;
 
     let mut registry = utils::get_registry();
     let entry = registry.find_entry_from_dest(target.to_path_buf());
 
-    if let Some(ent) = entry {
-        let backup_password = &ent.password;
-        let verify = backup_password.verify(&password);
-        if let Err(err) = verify {
-            return Err(SnapError::Password(err));
-        }else if let Ok(false) = verify {
-            return Err(SnapError::Password(crypto::password::PasswordError::IncorrectPassword));
-        }
+    let key = if let Some(ent) = entry {
+        utils::unlock_key(target, &password, Some(ent), !dry_run)?
     }else {
         return Err(SnapError::Delete("Target provided does not exist.".into()));
     };
 
-    let salt = utils::get_salt(&target);
-    let key = crypto::derive_key(&password, &salt);
-
     let blob_dir = target.join("blobs");
     let snapshot_dir = target.join("snapshot");
 
@@ -30,31 +71,19 @@ pub fn delete_data(nth: usize, target: &Path) -> Result<(), SnapError> {
         return Err(SnapError::Delete("Target does not contain any backup".into()));
     }
 
-    let nth_snapshot = utils::get_nth_recent_json_snapshot(nth, &snapshot_dir)?.map(|p| PathBuf::from(p));
+    let nth_snapshot = selector.resolve(&snapshot_dir)?;
 
     if let Some(snap_path) = nth_snapshot {
-        let snapshot = Snapshot::from_json_to_snapshot(&snap_path)?;
-        let snapshot_files = snapshot.files;
-
-        for (_, file) in snapshot_files {
-            if file.isupdated {
-                let hash_path = blob_dir.join(&file.hash);
-
-                let ciphertext = fs::read(&hash_path)?;
-                let nonce_bytes = file.nonce;
-
-                match crypto::decrypt_file_bytes(&ciphertext, &key, &nonce_bytes) {
-                    Ok(_) => {
-                        fs::remove_file(&hash_path)?;
-                    },
-                    Err(err) => {
-                        let message = "Could not decrypt file";
-                        return Err(SnapError::EncryptError(message.into(), err));
-                    }
-                }
-            }
+        let snapshot = Snapshot::from_json_to_snapshot(&snap_path, &key)?;
+
+        let plan = DeletePlan::load(&snap_path, &snapshot, &snapshot_dir, &key)?;
+
+        if dry_run {
+            report_dry_run(&plan);
+            return Ok(());
         }
-        fs::remove_file(snap_path)?;
+
+        remove_snapshot(target, &snapshot, &plan, &key)?;
 
         let mut ent = entry.unwrap().clone();
         ent.remove_snapshot();
@@ -62,10 +91,71 @@ pub fn delete_data(nth: usize, target: &Path) -> Result<(), SnapError> {
         registry.save_to_file()?;
     }
     else {
-        return Err(SnapError::Delete("Failed to delete backup: Invalid password or unreadble metadata.".into()));
+        return Err(SnapError::Delete(format!("Failed to delete backup: snapshot {selector} does not exist.")));
     }
 
     println!("Deletion complete.");
 
     Ok(())
 }
+
+/// Remove the snapshot of `plan` from the backup at `target` through a journal: its manifest and the blobs no other
+/// snapshot references, each decrypted with `key` first. The snapshot is then dropped from the garbage collector index.
+pub fn remove_snapshot(target: &Path, snapshot: &Snapshot, plan: &DeletePlan, key: &[u8]) -> Result<(), SnapError> {
+    let blob_dir = target.join("blobs");
+
+    // nothing is deleted until every blob to remove has been checked.
+    let mut journal = Journal::begin(target, "delete")?;
+
+    for (hash, path) in &plan.removed_blobs {
+        let hash_path = blob_dir.join(hash);
+
+        if !hash_path.exists() {
+            continue;
+        }
+
+        // every blob is decrypted in full, which authenticates all its segments.
+        let chunk = snapshot.files[path].blobs().into_iter().find(|chunk| chunk.hash == *hash);
+        let decrypted = chunk.ok_or_else(|| SnapError::Delete(format!("No file of the snapshot uses blob {hash}.")))
+            .and_then(|chunk| chunk.open(&blob_dir, key))
+            .and_then(|mut blob| Ok(io::copy(&mut blob, &mut io::sink())?));
+
+        match decrypted {
+            Ok(_) => {
+                journal.remove(&hash_path);
+            },
+            Err(err) => {
+                journal.rollback()?;
+                let message = "Could not decrypt file";
+                return Err(SnapError::EncryptError(message.into(), err.to_string().into()));
+            }
+        }
+    }
+    journal.remove(&plan.manifest);
+    journal.commit()?;
+
+    // versions introduced by the deleted manifest can no longer be pruned from it.
+    let mut garbage_info = GarbageLimit::from_json_to_gc().unwrap_or_else(|_| GarbageLimit::new());
+
+    if let Some(gc) = garbage_info.gc.get_mut(blob_dir.to_string_lossy().as_ref()) {
+        gc.forget_snapshot(&plan.manifest);
+        garbage_info.save()?;
+    }
+
+    Ok(())
+}
+
+fn report_dry_run(plan: &DeletePlan) {
+    println!("Dry run: nothing was deleted.");
+    println!("Would remove manifest {}", plan.manifest.file_name().unwrap_or_default().to_string_lossy());
+
+    println!("Would remove {} blobs:", plan.removed_blobs.len());
+    for (hash, path) in &plan.removed_blobs {
+        println!("  - {hash} ({})", path.display());
+    }
+
+    println!("Would keep {} blobs still referenced by other snapshots:", plan.kept_blobs.len());
+    for (hash, path) in &plan.kept_blobs {
+        println!("  = {hash} ({})", path.display());
+    }
+}
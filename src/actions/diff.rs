@@ -1,20 +1,238 @@
-/**
- * First the user will need to provide a password and it will have to be the one they used for backup
- * We will then get a backup entry based on the source path.
- * If there is None, there is no backup entry for that path so no change happened for that path,
- * else from what we receive, we can filter out the one whose hash matches the password hash
- * If there is none, the password is incorrect.
- * 
- * If there is some, we can now traverse the target, and match files to generate the diffs.
- * 
- * How Diff will affect backup and garbage collector.
- * The diffing capability gives a version history capability.
- * So this will lead to a modification to the garbage collector functionality
- * 1. When the limit is reached, we will merge the older diff we are about to delete and merge 
- *      with the current last file before we delete.
- * 2. This means I will have to implement an algorithm that will work on this functionality. 
- *      Since we are merging the files, it shouldn't be too complicated and we can merge them.
- *      I have to figure out how the diff crate works and what I need to do to make a successful merge.
- *      Right now my problem is if the file A has some additions and file B has deletions and I just need
- *      to make sure that new file from the merge uses this logic.
- */
\ No newline at end of file
+use std::{collections::BTreeSet, path::{Path, PathBuf}};
+
+use similar::TextDiff;
+
+use crate::utils::{self, error::SnapError, selector::SnapshotSelector, snapshot::Snapshot};
+
+/// File level changes between two snapshots of the same backup.
+///
+/// Every list is sorted by relative path so the output is stable between runs.
+#[derive(Debug, Default, PartialEq)]
+pub struct SnapshotDiff {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub unchanged: Vec<PathBuf>,
+}
+
+impl SnapshotDiff {
+    /// Compare the `files` maps of two snapshots.
+    ///
+    /// A file present in both snapshots is `modified` when its content, as told by `FileEntry::same_content`, or its kind
+    /// differs, `unchanged` otherwise.
+    pub fn between(old: &Snapshot, new: &Snapshot) -> Self {
+        let mut diff = Self::default();
+
+        let paths: BTreeSet<&PathBuf> = old.files.keys().chain(new.files.keys()).collect();
+
+        for path in paths {
+            match (old.files.get(path), new.files.get(path)) {
+                (None, Some(_)) => diff.added.push(path.clone()),
+                (Some(_), None) => diff.removed.push(path.clone()),
+                (Some(before), Some(after)) if !before.same_content(after) || before.kind != after.kind => diff.modified.push(path.clone()),
+                (Some(_), Some(_)) => diff.unchanged.push(path.clone()),
+                (None, None) => {}
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Byte level comparison of two versions of a binary file.
+#[derive(Debug, PartialEq)]
+pub struct ByteSummary {
+    pub old_size: usize,
+    pub new_size: usize,
+    /// Number of positions, within the shorter of the two versions, holding a different byte.
+    pub differing_bytes: usize,
+    pub first_difference: Option<usize>,
+}
+
+impl ByteSummary {
+    pub fn between(old: &[u8], new: &[u8]) -> Self {
+        let mut differing_bytes = 0;
+        let mut first_difference = None;
+
+        for (offset, (before, after)) in old.iter().zip(new.iter()).enumerate() {
+            if before != after {
+                differing_bytes += 1;
+                first_difference.get_or_insert(offset);
+            }
+        }
+
+        if first_difference.is_none() && old.len() != new.len() {
+            first_difference = Some(old.len().min(new.len()));
+        }
+
+        Self { old_size: old.len(), new_size: new.len(), differing_bytes, first_difference }
+    }
+}
+
+/// Compare the snapshots picked by `older` and `newer` at `origin`
+/// and print the files that were added, removed, modified or left unchanged between them.
+pub fn diff_snapshots(older: &SnapshotSelector, newer: &SnapshotSelector, origin: &Path) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+
+    let registry = utils::get_registry();
+    let entry = registry.find_entry_from_dest(origin.to_path_buf());
+
+    let key = if let Some(ent) = entry {
+        utils::unlock_key(origin, &password, Some(ent), false)?
+    } else {
+        let message = "No backup available at path provided";
+        return Err(SnapError::Diff(message.into()));
+    };
+
+    let ((old_path, old), (new_path, new)) = load_snapshots(older, newer, origin, &key)?;
+
+    let diff = SnapshotDiff::between(&old, &new);
+
+    println!("Comparing snapshot {} -> {}", snapshot_name(&old_path), snapshot_name(&new_path));
+
+    print_section("Added", "+", &diff.added);
+    print_section("Removed", "-", &diff.removed);
+    print_section("Modified", "~", &diff.modified);
+
+    if diff.is_empty() {
+        println!("No file changes between the two snapshots.");
+    }
+
+    println!(
+        "Summary: {} added, {} removed, {} modified, {} unchanged",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.modified.len(),
+        diff.unchanged.len(),
+    );
+
+    Ok(())
+}
+
+/// Decrypt and decompress the versions of `file` stored in the snapshots picked by `older` and `newer`
+/// at `origin` and print their differences.
+///
+/// Text files are printed as a unified diff, any other content gets a byte level summary.
+/// A file missing from one of the snapshots is treated as empty in that snapshot.
+pub fn diff_file(older: &SnapshotSelector, newer: &SnapshotSelector, origin: &Path, file: &Path) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+
+    let registry = utils::get_registry();
+    let entry = registry.find_entry_from_dest(origin.to_path_buf());
+
+    let (algorithm, key) = if let Some(ent) = entry {
+        let key = utils::unlock_key(origin, &password, Some(ent), false)?;
+        (ent.compression_algorithm.clone(), key)
+    } else {
+        let message = "No backup available at path provided";
+        return Err(SnapError::Diff(message.into()));
+    };
+
+    let engine = utils::generate_compression_engine(Some(algorithm))?.0;
+
+    let blobs_dir = origin.join("blobs");
+
+    let ((old_path, old), (new_path, new)) = load_snapshots(older, newer, origin, &key)?;
+
+    let file = &utils::normalize_snapshot_path(file);
+    let (old_entry, new_entry) = (old.files.get(file), new.files.get(file));
+
+    if old_entry.is_none() && new_entry.is_none() {
+        let message = format!("{} is not part of either snapshot.", file.display());
+        return Err(SnapError::Diff(message));
+    }
+
+    if old_entry.iter().chain(new_entry.iter()).any(|entry| !entry.has_blob()) {
+        let message = format!("{} is not a regular file.", file.display());
+        return Err(SnapError::Diff(message));
+    }
+
+    let old_content = match old_entry {
+        Some(file_entry) => file_entry.read_content(&blobs_dir, &key, engine.as_ref())?,
+        None => Vec::new(),
+    };
+    let new_content = match new_entry {
+        Some(file_entry) => file_entry.read_content(&blobs_dir, &key, engine.as_ref())?,
+        None => Vec::new(),
+    };
+
+    let old_label = format!("{} ({})", file.display(), snapshot_name(&old_path));
+    let new_label = format!("{} ({})", file.display(), snapshot_name(&new_path));
+
+    if old_content == new_content {
+        println!("No changes to {} between the two snapshots.", file.display());
+        return Ok(());
+    }
+
+    match (as_text(&old_content), as_text(&new_content)) {
+        (Some(old_text), Some(new_text)) => {
+            let text_diff = TextDiff::from_lines(old_text, new_text);
+            print!("{}", text_diff.unified_diff().context_radius(3).header(&old_label, &new_label));
+        },
+        _ => {
+            let summary = ByteSummary::between(&old_content, &new_content);
+
+            println!("Binary file {} differs", file.display());
+            println!("  size: {} -> {} bytes", summary.old_size, summary.new_size);
+            println!("  differing bytes: {}", summary.differing_bytes);
+            if let Some(offset) = summary.first_difference {
+                println!("  first difference at offset: {offset:#x}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Treat `content` as text when it is valid UTF-8 without any NUL byte.
+fn as_text(content: &[u8]) -> Option<&str> {
+    if content.contains(&0) {
+        return None;
+    }
+
+    std::str::from_utf8(content).ok()
+}
+
+type LoadedSnapshot = (PathBuf, Snapshot);
+
+fn load_snapshots(older: &SnapshotSelector, newer: &SnapshotSelector, origin: &Path, key: &[u8]) -> Result<(LoadedSnapshot, LoadedSnapshot), SnapError> {
+    let snapshot_dir = origin.join("snapshot");
+
+    if !snapshot_dir.exists() {
+        return Err(SnapError::Diff("Target does not contain any snapshot".into()));
+    }
+
+    let old_path = snapshot_path(older, &snapshot_dir)?;
+    let new_path = snapshot_path(newer, &snapshot_dir)?;
+
+    let old = Snapshot::from_json_to_snapshot(&old_path, key)?;
+    let new = Snapshot::from_json_to_snapshot(&new_path, key)?;
+
+    Ok(((old_path, old), (new_path, new)))
+}
+
+fn snapshot_path(selector: &SnapshotSelector, snapshot_dir: &Path) -> Result<PathBuf, SnapError> {
+    match selector.resolve(snapshot_dir)? {
+        Some(path) => Ok(path),
+        None => Err(SnapError::Diff(format!("Snapshot {selector} does not exist."))),
+    }
+}
+
+fn snapshot_name(path: &Path) -> String {
+    path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+fn print_section(title: &str, marker: &str, paths: &[PathBuf]) {
+    if paths.is_empty() {
+        return;
+    }
+
+    println!("{title}:");
+    for path in paths {
+        println!("  {marker} {}", path.display());
+    }
+}
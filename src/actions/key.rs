@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use crate::{crypto::{keyslot::KeyStore, password::Password}, utils::{self, error::SnapError}};
+
+/// Add a key slot to the backup at `origin` so it can also be unlocked with a new password.
+pub fn add_key(origin: &Path) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+    let (mut store, _, master_key) = open_store(origin, &password)?;
+
+    let new_password = utils::read_new_password()?;
+    let id = store.add_slot(&master_key, &new_password);
+    save_store(origin, &store)?;
+
+    println!("Added key slot {id}.");
+
+    Ok(())
+}
+
+/// Remove the key slot `slot` from the backup at `origin`, or the slot opened by the entered password when `slot` is `None`.
+pub fn remove_key(origin: &Path, slot: Option<u32>) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+    let (mut store, unlocked_slot, _) = open_store(origin, &password)?;
+
+    let id = slot.unwrap_or(unlocked_slot);
+
+    if store.slots.len() <= 1 {
+        return Err(SnapError::KeyStore("Cannot remove the last key slot of a backup.".into()));
+    }
+
+    if !store.remove_slot(id) {
+        return Err(SnapError::KeyStore(format!("Key slot {id} does not exist.")));
+    }
+    save_store(origin, &store)?;
+
+    println!("Removed key slot {id}.");
+
+    Ok(())
+}
+
+/// Replace the password of the key slot opened by the entered password with a new one.
+pub fn change_key(origin: &Path) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+    let (mut store, id, master_key) = open_store(origin, &password)?;
+
+    let new_password = utils::read_new_password()?;
+    store.replace_slot(id, &master_key, &new_password);
+    save_store(origin, &store)?;
+
+    println!("Password of key slot {id} changed.");
+
+    Ok(())
+}
+
+/// Print the key slots of the backup at `origin`. No password is needed since slots only hold wrapped keys.
+pub fn list_keys(origin: &Path) -> Result<(), SnapError> {
+    let store = match KeyStore::load(origin)? {
+        Some(store) => store,
+        None => return Err(SnapError::KeyStore("No key store found for this backup.".into())),
+    };
+
+    println!("Key slots of {:?}:", origin.display());
+    for slot in store.slots {
+        println!("- Slot: {}\n Created: {}", slot.id, slot.created);
+    }
+
+    Ok(())
+}
+
+/// Unlock the key store of the backup at `origin`, migrating a backup made before key slots first.
+/// Returns the store, the id of the slot opened by `password` and the master key.
+fn open_store(origin: &Path, password: &str) -> Result<(KeyStore, u32, [u8; 32]), SnapError> {
+    let registry = utils::get_registry();
+    let entry = registry.find_entry_from_dest(origin.to_path_buf());
+
+    if entry.is_none() {
+        let message = "No backup available at path provided";
+        return Err(SnapError::KeyStore(message.into()));
+    }
+
+    utils::unlock_key(origin, password, entry, true)?;
+
+    let store = match KeyStore::load(origin)? {
+        Some(store) => store,
+        None => return Err(SnapError::KeyStore("No key store found for this backup.".into())),
+    };
+    let (id, master_key) = store.unlock(password)?;
+
+    Ok((store, id, master_key))
+}
+
+/// Save the key store and drop any pre key slot password hash from the registry,
+/// since it no longer matches the passwords of the backup.
+fn save_store(origin: &Path, store: &KeyStore) -> Result<(), SnapError> {
+    store.save(origin)?;
+
+    let mut registry = utils::get_registry();
+
+    if let Some(ent) = registry.find_entry_from_dest(origin.to_path_buf()) {
+        let mut ent = ent.clone();
+        ent.password = Password::default();
+        registry.add_backup(ent);
+        registry.save_to_file()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::{collections::BTreeSet, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobMatcher};
+
+use crate::utils::{self, error::SnapError, selector::SnapshotSelector, snapshot::{EntryKind, FileEntry, Snapshot}};
+
+/// Options of `snapsafe ls`.
+#[derive(Debug, Default)]
+pub struct LsOptions {
+    /// directory (or file) inside the snapshot to list, the snapshot root when `None`.
+    pub prefix: Option<PathBuf>,
+    /// list the whole subtree instead of the direct children of `prefix`.
+    pub recursive: bool,
+    /// show size, modified time and version hash of each file.
+    pub long: bool,
+    /// only keep files whose path relative to the snapshot root matches this glob.
+    pub glob: Option<String>,
+}
+
+/// Print the files of the snapshot picked by `selector` at `origin` as a directory tree.
+/// Only the manifest is decrypted, no blob is read.
+pub fn list_files(selector: &SnapshotSelector, origin: &Path, options: &LsOptions) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+
+    let registry = utils::get_registry();
+    let entry = registry.find_entry_from_dest(origin.to_path_buf());
+
+    let key = if let Some(ent) = entry {
+        utils::unlock_key(origin, &password, Some(ent), false)?
+    } else {
+        let message = "No backup available at path provided";
+        return Err(SnapError::Command(message.into()));
+    };
+
+    let snapshot = match selector.resolve(&origin.join("snapshot"))? {
+        Some(path) => Snapshot::from_json_to_snapshot(&path, &key)?,
+        None => return Err(SnapError::Command(format!("Snapshot {selector} does not exist."))),
+    };
+
+    let lines = render_tree(&snapshot, options)?;
+
+    if lines.is_empty() {
+        println!("No files match in this snapshot.");
+    }
+
+    for line in lines {
+        println!("{line}");
+    }
+
+    Ok(())
+}
+
+/// Build the lines printed by `snapsafe ls` for `snapshot`.
+///
+/// Directories end with `/` and the entries of a directory are indented by two spaces per level.
+/// In long format symlinks show their target after `->`.
+pub fn render_tree(snapshot: &Snapshot, options: &LsOptions) -> Result<Vec<String>, SnapError> {
+    let matcher = match &options.glob {
+        Some(pattern) => Some(compile_glob(pattern)?),
+        None => None,
+    };
+
+    let prefix = options.prefix.as_deref().map(utils::normalize_snapshot_path).unwrap_or_default();
+
+    let mut files: Vec<(PathBuf, &FileEntry)> = snapshot.files.iter()
+        .filter(|(path, _)| matcher.as_ref().map(|m| m.is_match(path)).unwrap_or(true))
+        // the directory being listed is not one of its own entries.
+        .filter(|(path, entry)| !(*path == &prefix && entry.kind == EntryKind::Dir))
+        .filter_map(|(path, entry)| {
+            if path == &prefix {
+                let name = path.file_name().map(PathBuf::from).unwrap_or_default();
+                return Some((name, entry));
+            }
+            path.strip_prefix(&prefix).ok().map(|rel| (rel.to_path_buf(), entry))
+        })
+        .collect();
+
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut lines = Vec::new();
+
+    if !options.recursive {
+        let mut seen_dirs = BTreeSet::new();
+
+        for (rel, entry) in files {
+            let mut components = rel.components();
+            let first = components.next().map(|c| c.as_os_str().to_string_lossy().to_string()).unwrap_or_default();
+
+            if components.next().is_some() || entry.kind == EntryKind::Dir {
+                if seen_dirs.insert(first.clone()) {
+                    lines.push(format_dir(&first, 0, options.long));
+                }
+            } else {
+                lines.push(format_file(&first, entry, 0, options.long));
+            }
+        }
+
+        return Ok(lines);
+    }
+
+    let mut open_dirs: Vec<String> = Vec::new();
+
+    for (rel, entry) in files {
+        let names: Vec<String> = rel.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect();
+        let (file_name, dirs) = names.split_last().unwrap();
+
+        // a directory entry only opens the directory, empty ones included.
+        let dirs = if entry.kind == EntryKind::Dir { names.as_slice() } else { dirs };
+
+        let common = open_dirs.iter().zip(dirs.iter()).take_while(|(a, b)| a == b).count();
+        open_dirs.truncate(common);
+
+        for dir in &dirs[common..] {
+            lines.push(format_dir(dir, open_dirs.len(), options.long));
+            open_dirs.push(dir.clone());
+        }
+
+        if entry.kind != EntryKind::Dir {
+            lines.push(format_file(file_name, entry, dirs.len(), options.long));
+        }
+    }
+
+    Ok(lines)
+}
+
+fn compile_glob(pattern: &str) -> Result<GlobMatcher, SnapError> {
+    Glob::new(pattern)
+        .map(|glob| glob.compile_matcher())
+        .map_err(|err| SnapError::Command(format!("Invalid glob pattern: {err}")))
+}
+
+fn format_dir(name: &str, depth: usize, long: bool) -> String {
+    let indent = "  ".repeat(depth);
+
+    if long {
+        format!("{:>12}  {:<19}  {:<12}  {indent}{name}/", "-", "-", "-")
+    } else {
+        format!("{indent}{name}/")
+    }
+}
+
+fn format_file(name: &str, entry: &FileEntry, depth: usize, long: bool) -> String {
+    let indent = "  ".repeat(depth);
+
+    if long {
+        let modified: DateTime<Utc> = entry.modified.into();
+        let hash: String = if entry.has_blob() { entry.hash.chars().take(12).collect() } else { "-".into() };
+        let name = match &entry.kind {
+            EntryKind::Symlink { target } => format!("{name} -> {}", target.display()),
+            _ => name.to_string(),
+        };
+
+        format!("{:>12}  {:<19}  {:<12}  {indent}{name}", entry.size, modified.format("%Y-%m-%d %H:%M:%S"), hash)
+    } else {
+        format!("{indent}{name}")
+    }
+}
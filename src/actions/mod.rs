@@ -6,31 +6,36 @@
 // if we don't have a record of that file's timestamp, proceed to hashing and back it up, 
 // if timestamp has changed, check for hash changes and either backup or skip
 
-use std::{io, path::Path};
+use std::{io, path::{Path, PathBuf}};
 
-use crate::utils::{self, config::Config, error::SnapError};
+use crate::utils::{self, config::Config, error::SnapError, rules::BackupRules, selector::SnapshotSelector};
 
 pub mod backup;
+pub mod cat;
 pub mod config;
 pub mod delete;
+pub mod diff;
+pub mod key;
+pub mod ls;
 pub mod restore;
+pub mod snapshots;
+pub mod verify;
 
-pub fn backup(src: &Path, dest: &Path, comp: Option<String>, config: Option<Config>) -> Result<(), SnapError> {
-    backup::backup_data(src, dest, comp, config)
+pub fn backup(src: &Path, dest: &Path, comp: Option<String>, config: Option<Config>, rules: &BackupRules, dry_run: bool) -> Result<(), SnapError> {
+    backup::backup_data(src, dest, comp, config, rules, dry_run)
 }
 
 pub fn config(local: bool) -> Result<Config, SnapError> {
     config::generate_config(local)
 }
 
-pub fn restore(nth: u8, src: &Path, output_dir: &Path) -> Result<(), SnapError> {
-    let nth = (nth - 1) as usize;
-    restore::restore(nth, src, output_dir)
+pub fn restore(selector: &SnapshotSelector, src: &Path, output_dir: &Path, options: &restore::RestoreOptions) -> Result<(), SnapError> {
+    restore::restore(selector, src, output_dir, options)
 }
 
-pub fn delete(nth: u8, target: &Path, force: bool) -> Result<(), SnapError> {
-    // DO YOU REALLY WANT TO DELETE?
-    let delete_confirm = if !force {
+pub fn delete(selector: &SnapshotSelector, target: &Path, force: bool, dry_run: bool) -> Result<(), SnapError> {
+    // DO YOU REALLY WANT TO DELETE? a dry run deletes nothing, so there is nothing to confirm.
+    let delete_confirm = if !force && !dry_run {
         let input = utils::prompt_for_input("Are you sure you want to permanently delete this backup? [y/N] ");
         match input {
             Some(response) => {
@@ -39,7 +44,7 @@ pub fn delete(nth: u8, target: &Path, force: bool) -> Result<(), SnapError> {
             None => false,
         }
     } else {
-        force
+        true
     };
 
     if !delete_confirm {
@@ -47,10 +52,47 @@ pub fn delete(nth: u8, target: &Path, force: bool) -> Result<(), SnapError> {
         return Ok(());
     }
 
-    let nth = (nth - 1) as usize;
-    delete::delete_data(nth, target)    
+    delete::delete_data(selector, target, dry_run)
 }
 
+pub fn diff(from: &SnapshotSelector, to: &SnapshotSelector, origin: &Path, file: Option<PathBuf>) -> Result<(), SnapError> {
+    match file {
+        Some(file) => diff::diff_file(from, to, origin, &file),
+        None => diff::diff_snapshots(from, to, origin),
+    }
+}
+
+pub fn key_add(origin: &Path) -> Result<(), SnapError> {
+    key::add_key(origin)
+}
+
+pub fn key_remove(origin: &Path, slot: Option<u32>) -> Result<(), SnapError> {
+    key::remove_key(origin, slot)
+}
+
+pub fn key_change(origin: &Path) -> Result<(), SnapError> {
+    key::change_key(origin)
+}
+
+pub fn key_list(origin: &Path) -> Result<(), SnapError> {
+    key::list_keys(origin)
+}
+
+pub fn cat(selector: &SnapshotSelector, origin: &Path, file: &Path) -> Result<(), SnapError> {
+    cat::cat_file(selector, origin, file)
+}
+
+pub fn ls(selector: &SnapshotSelector, origin: &Path, options: &ls::LsOptions) -> Result<(), SnapError> {
+    ls::list_files(selector, origin, options)
+}
+
+pub fn snapshots(origin: &Path) -> Result<(), SnapError> {
+    snapshots::list_snapshots(origin)
+}
+
+pub fn verify(selector: Option<&SnapshotSelector>, origin: &Path) -> Result<(), SnapError> {
+    verify::verify_data(selector, origin)
+}
 
 pub fn list() -> io::Result<()> {
     let registry = utils::get_registry().registry;
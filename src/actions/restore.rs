@@ -1,35 +1,401 @@
-use std::{fs, path::{Path, PathBuf}};
-
-use crate::{crypto, utils::{self, error::SnapError, snapshot::Snapshot}};
-
-/// Restore the nth version of a backup at the location: `src`
-/// Returns: `Ok(())` when successful or `Err(SnapError)` on any kind of failure.
-/// 
-/// if `nth` is 0, we restore the latest backup. 
-/// With default `GarbageCollectorConfig`, we keep 3 versions of each backup and hence 
-/// nth is in the range 0..3
-/// 
-/// When a user defines a garbage collector config limit, `Lim`, we define nth to be in the range `0..Lim`.
-/// We make decompresssion based on the compression algorithm used during backup.
-/// 
-/// Decryption first occurs then decompression will take place.
-/// the decompressed content is written to a file and saved in a path format similar to when backup occured. 
-/// The `output_dir` is where the final files will be written to.
-pub fn restore(nth: usize, src: &Path, output_dir: &Path) -> Result<(), SnapError> {
+use std::{collections::HashMap, fmt, fs, io::{self, BufWriter, Write}, os::unix::fs::symlink, path::{Component, Path, PathBuf}, str::FromStr};
+
+use chrono::Utc;
+use nix::{sys::stat::{mknod, Mode, SFlag}, unistd::mkfifo};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use walkdir::WalkDir;
+
+use crate::{actions::delete::{self, DeletePlan}, utils::{self, error::SnapError, metadata::FileMetadata, selector::SnapshotSelector, snapshot::{EntryKind, FileEntry, Snapshot}}};
+
+/// Options of `snapsafe restore`.
+#[derive(Debug, Default)]
+pub struct RestoreOptions {
+    /// remove the restored snapshot from the backup once it has been restored.
+    pub pop: bool,
+    /// the files of the snapshot to restore.
+    pub filter: RestoreFilter,
+    /// what to do with files that already exist in the output directory.
+    pub on_conflict: ConflictPolicy,
+    /// who owns the restored files.
+    pub owner: OwnerOptions,
+    /// number of files decrypted and decompressed at once, 0 for one per available CPU.
+    pub jobs: usize,
+}
+
+/// Who owns the files a restore writes.
+#[derive(Clone, Debug, Default)]
+pub struct OwnerOptions {
+    /// leave the restored files owned by the user running the restore.
+    pub skip: bool,
+    /// uids recorded in the snapshot mapped to the uid the restored files get.
+    pub uid_map: HashMap<u32, u32>,
+    /// gids recorded in the snapshot mapped to the gid the restored files get.
+    pub gid_map: HashMap<u32, u32>,
+}
+
+impl OwnerOptions {
+    pub fn new(skip: bool, map_uid: &[IdMapping], map_gid: &[IdMapping]) -> Self {
+        let to_map = |mappings: &[IdMapping]| mappings.iter().map(|m| (m.from, m.to)).collect();
+
+        Self { skip, uid_map: to_map(map_uid), gid_map: to_map(map_gid) }
+    }
+
+    /// The `(uid, gid)` a file with `metadata` is restored with, `None` when ownership is not restored.
+    pub fn owner_of(&self, metadata: &FileMetadata) -> Option<(u32, u32)> {
+        if self.skip {
+            return None;
+        }
+
+        let uid = self.uid_map.get(&metadata.uid).copied().unwrap_or(metadata.uid);
+        let gid = self.gid_map.get(&metadata.gid).copied().unwrap_or(metadata.gid);
+
+        Some((uid, gid))
+    }
+}
+
+/// One `OLD:NEW` value of `--map-uid` or `--map-gid`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdMapping {
+    pub from: u32,
+    pub to: u32,
+}
+
+impl FromStr for IdMapping {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid id mapping '{input}', expected OLD:NEW with both ids numbers.");
+
+        let (from, to) = input.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            from: from.trim().parse().map_err(|_| invalid())?,
+            to: to.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// What a restore does with a file that already exists in the output directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConflictPolicy {
+    /// replace the existing file with the restored one.
+    #[default]
+    Overwrite,
+    /// keep the existing file.
+    Skip,
+    /// keep the existing file when it was modified after the version stored in the snapshot.
+    KeepNewer,
+    /// keep the existing file and write the restored one next to it as `<name>.restored-<timestamp>`.
+    Rename,
+    /// abort the restore before writing anything.
+    Fail,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "keep-newer" => Ok(ConflictPolicy::KeepNewer),
+            "rename" => Ok(ConflictPolicy::Rename),
+            "fail" => Ok(ConflictPolicy::Fail),
+            _ => Err(format!("Invalid conflict policy '{input}', expected one of overwrite, skip, keep-newer, rename, fail.")),
+        }
+    }
+}
+
+/// What happened to one file of the snapshot during a restore.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RestoreOutcome {
+    /// no file existed at the target.
+    Written,
+    Overwritten,
+    Skipped,
+    KeptNewer,
+    /// the restored file was written to this path instead.
+    Renamed(PathBuf),
+}
+
+impl fmt::Display for RestoreOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreOutcome::Written => write!(f, "written"),
+            RestoreOutcome::Overwritten => write!(f, "overwritten"),
+            RestoreOutcome::Skipped => write!(f, "skipped"),
+            RestoreOutcome::KeptNewer => write!(f, "kept newer"),
+            RestoreOutcome::Renamed(path) => write!(f, "renamed to {}", path.display()),
+        }
+    }
+}
+
+/// Decide where the version of `entry` restored to `target` is written, `None` when it is not written at all.
+///
+/// `suffix` is the timestamp appended to renamed copies, shared by every file of one restore.
+pub fn resolve_conflict(target: &Path, entry: &FileEntry, policy: ConflictPolicy, suffix: &str) -> Result<(Option<PathBuf>, RestoreOutcome), SnapError> {
+    if !target.try_exists()? {
+        return Ok((Some(target.to_path_buf()), RestoreOutcome::Written));
+    }
+
+    match policy {
+        ConflictPolicy::Overwrite => Ok((Some(target.to_path_buf()), RestoreOutcome::Overwritten)),
+        ConflictPolicy::Skip => Ok((None, RestoreOutcome::Skipped)),
+        ConflictPolicy::KeepNewer => {
+            if fs::metadata(target)?.modified()? > entry.modified {
+                Ok((None, RestoreOutcome::KeptNewer))
+            } else {
+                Ok((Some(target.to_path_buf()), RestoreOutcome::Overwritten))
+            }
+        },
+        ConflictPolicy::Rename => {
+            let mut name = target.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".restored-{suffix}"));
+            let renamed = target.with_file_name(name);
+
+            Ok((Some(renamed.clone()), RestoreOutcome::Renamed(renamed)))
+        },
+        ConflictPolicy::Fail => {
+            let message = format!("{} already exists in the output directory.", target.display());
+            Err(SnapError::Restore(message))
+        },
+    }
+}
+
+/// Selects the files of a snapshot a restore decrypts and writes.
+///
+/// A file is selected when it is below one of `paths` (or `paths` is empty), matches one of the
+/// `include` globs (or there is none) and matches none of the `exclude` globs.
+#[derive(Debug, Default)]
+pub struct RestoreFilter {
+    paths: Vec<PathBuf>,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl RestoreFilter {
+    pub fn new(paths: Vec<PathBuf>, include: &[String], exclude: &[String]) -> Result<Self, SnapError> {
+        let paths = paths.iter().map(|p| utils::normalize_snapshot_path(p)).collect();
+
+        Ok(Self { paths, include: build_glob_set(include)?, exclude: build_glob_set(exclude)? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.include.is_none() && self.exclude.is_none()
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let in_paths = self.paths.is_empty() || self.paths.iter().any(|p| path.starts_with(p));
+        let included = self.include.as_ref().map(|set| set.is_match(path)).unwrap_or(true);
+        let excluded = self.exclude.as_ref().map(|set| set.is_match(path)).unwrap_or(false);
+
+        in_paths && included && !excluded
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<Option<GlobSet>, SnapError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|err| SnapError::Restore(format!("Invalid glob pattern: {err}")))?;
+        builder.add(glob);
+    }
+
+    builder.build()
+        .map(Some)
+        .map_err(|err| SnapError::Restore(format!("Invalid glob pattern: {err}")))
+}
+
+/// Directory next to the output directory that a restore writes every file to before moving them into place,
+/// so a failed restore never leaves half restored files in the output directory.
+struct Staging {
+    dir: PathBuf,
+    /// name the staging directory is renamed to when the restore fails.
+    failed: PathBuf,
+}
+
+impl Staging {
+    fn create(output_dir: &Path, stamp: &str) -> Result<Self, SnapError> {
+        let (parent, name) = match (output_dir.parent(), output_dir.file_name()) {
+            (Some(parent), Some(name)) => (parent, name.to_string_lossy()),
+            _ => return Err(SnapError::Restore("Cannot restore into a root directory.".into())),
+        };
+
+        fs::create_dir_all(parent)?;
+
+        let dir = parent.join(format!(".{name}.restore-{stamp}"));
+        let failed = parent.join(format!("{name}.restore-{stamp}.failed"));
+
+        fs::create_dir(&dir)?;
+
+        Ok(Self { dir, failed })
+    }
+
+    /// Where `rel_path` is staged. Manifest paths are only trusted as far as they name entries below the staging
+    /// directory, so a path with `..`, a root or a prefix never reaches the file system.
+    fn path(&self, rel_path: &Path) -> io::Result<PathBuf> {
+        if rel_path.as_os_str().is_empty() || !rel_path.components().all(|part| matches!(part, Component::Normal(_))) {
+            let message = format!("{} is not a path inside the output directory.", rel_path.display());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+
+        Ok(self.dir.join(rel_path))
+    }
+
+    /// Create the file at `rel_path` in the staging directory, let `write_content` stream its content into it
+    /// and check as many bytes as it reports reached the disk.
+    fn write(&self, rel_path: &Path, write_content: impl FnOnce(&mut dyn Write) -> Result<u64, SnapError>) -> Result<(), SnapError> {
+        let path = self.path(rel_path)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = BufWriter::new(fs::File::create(&path)?);
+        let written = write_content(&mut file)?;
+        file.flush()?;
+        drop(file);
+
+        if fs::metadata(&path)?.len() != written {
+            return Err(SnapError::Restore(format!("{} was not fully written.", rel_path.display())));
+        }
+
+        Ok(())
+    }
+
+    /// Create `rel_path` in the staging directory as a hard link to the staged file at `original`.
+    fn link(&self, original: &Path, rel_path: &Path) -> io::Result<()> {
+        let path = self.path(rel_path)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::hard_link(self.path(original)?, path)
+    }
+
+    /// Create the directory, symlink or special file described by `kind` at `rel_path` in the staging directory.
+    fn make(&self, rel_path: &Path, kind: &EntryKind) -> io::Result<()> {
+        let path = self.path(rel_path)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // permissions are set along with the rest of the metadata once the entry exists.
+        let mode = Mode::from_bits_truncate(0o600);
+
+        match kind {
+            EntryKind::File => fs::write(&path, []),
+            EntryKind::Dir => fs::create_dir_all(&path),
+            EntryKind::Symlink { target } => symlink(target, &path),
+            EntryKind::Fifo => Ok(mkfifo(&path, mode)?),
+            EntryKind::CharDevice { rdev } => Ok(mknod(&path, SFlag::S_IFCHR, mode, *rdev)?),
+            EntryKind::BlockDevice { rdev } => Ok(mknod(&path, SFlag::S_IFBLK, mode, *rdev)?),
+        }
+    }
+
+    /// Move the staged entries into `output_dir`. A missing output directory is replaced by the staging
+    /// directory in one rename, otherwise every staged entry but directories is renamed over its target.
+    ///
+    /// Targets that cannot be replaced, a directory where a file is staged or the other way around, are found
+    /// before anything is moved. When a rename still fails, the error lists the files already moved.
+    fn commit(&self, output_dir: &Path) -> Result<(), SnapError> {
+        if !output_dir.try_exists()? {
+            fs::rename(&self.dir, output_dir)?;
+            return Ok(());
+        }
+
+        let mut entries = Vec::new();
+        let mut conflicts = Vec::new();
+
+        for entry in WalkDir::new(&self.dir).min_depth(1) {
+            let entry = entry.map_err(io::Error::from)?;
+            let rel_path = entry.path().strip_prefix(&self.dir).unwrap().to_path_buf();
+
+            // a symlink is replaced like a file, whatever it points to.
+            let is_dir = entry.file_type().is_dir();
+            match fs::symlink_metadata(output_dir.join(&rel_path)) {
+                Ok(target) if target.is_dir() != is_dir => conflicts.push(rel_path.display().to_string()),
+                Ok(_) => {},
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+                Err(err) => return Err(err.into()),
+            }
+
+            entries.push((rel_path, is_dir));
+        }
+
+        if !conflicts.is_empty() {
+            let message = format!("{} files cannot replace what is in the output directory: {}.", conflicts.len(), conflicts.join(", "));
+            return Err(SnapError::Restore(message));
+        }
+
+        let mut moved = Vec::new();
+
+        for (rel_path, is_dir) in entries {
+            let target = output_dir.join(&rel_path);
+
+            let renamed = if is_dir {
+                fs::create_dir_all(&target)
+            } else {
+                fs::rename(self.dir.join(&rel_path), &target)
+            };
+
+            if let Err(err) = renamed {
+                let message = match moved.len() {
+                    0 => format!("{err}."),
+                    _ => format!("{err}. {} files were already moved into the output directory: {}.", moved.len(), moved.join(", ")),
+                };
+                return Err(SnapError::Restore(message));
+            }
+
+            if !is_dir {
+                moved.push(rel_path.display().to_string());
+            }
+        }
+
+        fs::remove_dir_all(&self.dir)?;
+
+        Ok(())
+    }
+
+    /// Mark the staging directory as a failed restore and return where it was left.
+    fn abandon(&self) -> PathBuf {
+        match fs::rename(&self.dir, &self.failed) {
+            Ok(()) => self.failed.clone(),
+            Err(_) => self.dir.clone(),
+        }
+    }
+}
+
+/// Restore the snapshot picked by `selector` from the backup at `src` into `output_dir`.
+///
+/// Only the files selected by `options.filter` are decrypted and written. Files already present in `output_dir`
+/// are handled according to `options.on_conflict` and reported one per line. The recorded permissions, timestamps
+/// and extended attributes of every file are re-applied, along with its owner as set by `options.owner`.
+///
+/// Files are first written to a staging directory next to `output_dir` and checked against the snapshot,
+/// then moved into `output_dir` once all of them were restored. When any file fails, or a staged file and what
+/// `output_dir` holds at its path are not both directories or both not, nothing is moved and the staging
+/// directory is left with a `.failed` suffix. Should a move fail after that, the error names the files moved.
+///
+/// The backup is left untouched, so a snapshot can be restored any number of times.
+/// When `options.pop` is set the restored snapshot is removed from the backup afterwards.
+pub fn restore(selector: &SnapshotSelector, src: &Path, output_dir: &Path, options: &RestoreOptions) -> Result<(), SnapError> {
     let password = utils::read_password()?;
 
     let mut registry = utils::get_registry();
     let entry = registry.find_entry_from_dest(src.to_path_buf());
 
-    let algorithm = if let Some(ent) = entry {
-        let backup_password = &ent.password;
-        let verify = backup_password.verify(&password);
-        if let Err(err) = verify {
-            return Err(SnapError::Password(err));
-        }else if let Ok(false) = verify {
-            return Err(SnapError::Password(crypto::password::PasswordError::IncorrectPassword));
-        }
-        ent.compression_algorithm.clone()
+    let (algorithm, key) = if let Some(ent) = entry {
+        let key = utils::unlock_key(src, &password, Some(ent), false)?;
+        (ent.compression_algorithm.clone(), key)
     }else {
         let message = "No backup available at path provided";
         return Err(SnapError::Restore(message.into()));
@@ -37,65 +403,187 @@ pub fn restore(nth: usize, src: &Path, output_dir: &Path) -> Result<(), SnapErro
 
     let engine = utils::generate_compression_engine(Some(algorithm))?.0;
 
-
-    if !output_dir.try_exists().unwrap_or(false) {
-        let _ = fs::create_dir_all(&output_dir)?;
-    }
-
-    let salt = utils::get_salt(&src);
-    let key = crypto::derive_key(&password, &salt);
+    let output_dir = std::path::absolute(output_dir)?;
 
     let blobs_dir = src.join("blobs");
     let snapshot_dir = src.join("snapshot");
     
-    let nth_snapshot = if snapshot_dir.try_exists().unwrap() {
-        utils::get_nth_recent_json_snapshot(nth, &snapshot_dir)?.map(|path| PathBuf::from(path))
-    } else {
-        None
-    };
+    let nth_snapshot = selector.resolve(&snapshot_dir)?;
 
     if let Some(snapshot_path) = nth_snapshot {
-        let snapshot = Snapshot::from_json_to_snapshot(&snapshot_path)?;
-        let snapshot_files = snapshot.files;
-        
-        for (path, file_entry) in snapshot_files {
-            let hash_path = blobs_dir.join(&file_entry.hash);
-
-            let ciphertext = fs::read(&hash_path)?;
-            let nonce_bytes = file_entry.nonce;
-
-            println!("{:?}", ciphertext);
-
-            match crypto::decrypt_file_bytes(&ciphertext, &key, &nonce_bytes) {
-                Ok(decrytped) => {
-                    let rel_target = output_dir.join(path);
-
-                    if let Some(parent) = rel_target.parent() {
-                        let _ = fs::create_dir_all(parent)?;
-                    }
-
-                    let decompressed_content = engine.decompress(&decrytped)?;
-                    
-                    if let Err(err) = fs::write(&rel_target, &decompressed_content) {
-                        return Err(SnapError::IOError(err));
-                    }
-                },
-                Err(err) => {
-                    let message = "Failed to decrypt target file";
-                    return Err(SnapError::EncryptError(message.into(), err));
+        let snapshot = Snapshot::from_json_to_snapshot(&snapshot_path, &key)?;
+        let total = snapshot.files.values().filter(|entry| entry.kind != EntryKind::Dir).count();
+
+        let mut snapshot_files: Vec<_> = snapshot.files.into_iter()
+            .filter(|(path, _)| options.filter.matches(path))
+            .collect();
+        snapshot_files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        if snapshot_files.is_empty() {
+            let message = "No file in the snapshot matches the paths and patterns provided.";
+            return Err(SnapError::Restore(message.into()));
+        }
+
+        // directories are merged with the ones already in the output directory instead of conflicting with them.
+        let (dirs, snapshot_files): (Vec<_>, Vec<_>) = snapshot_files.into_iter()
+            .partition(|(_, entry)| entry.kind == EntryKind::Dir);
+
+        let restored = snapshot_files.len();
+
+        // a failing policy aborts before any file is written.
+        if options.on_conflict == ConflictPolicy::Fail {
+            let conflicts: Vec<_> = snapshot_files.iter()
+                .filter(|(path, _)| output_dir.join(path).exists())
+                .map(|(path, _)| path.display().to_string())
+                .collect();
+
+            if !conflicts.is_empty() {
+                let message = format!("{} files already exist in the output directory: {}", conflicts.len(), conflicts.join(", "));
+                return Err(SnapError::Restore(message));
+            }
+        }
+
+        let stamp = Utc::now().format("%Y%m%dT%H%M%S").to_string();
+        let staging = Staging::create(&output_dir, &stamp)?;
+        let mut outcomes = Vec::with_capacity(restored);
+        let mut owner_failures = 0;
+        let mut xattr_failures = 0;
+        let mut device_failures = 0;
+
+        // only directories the restore creates get their recorded metadata, once their content is in place.
+        let new_dirs: Vec<_> = dirs.iter().filter(|(path, _)| !output_dir.join(path).exists()).collect();
+
+        let staged: Result<(), SnapError> = dirs.iter().try_for_each(|(path, _)| Ok(staging.make(path, &EntryKind::Dir)?));
+
+        // hard links come last so the first path of their group is planned before them.
+        let (links, others): (Vec<_>, Vec<_>) = snapshot_files.iter().partition(|(_, entry)| entry.hard_link.is_some());
+        let mut staged_files = HashMap::<&PathBuf, PathBuf>::new();
+        let mut plan = Vec::with_capacity(restored);
+
+        let staged = staged.and_then(|_| others.into_iter().chain(links).try_for_each(|(path, file_entry)| {
+            let (write_to, outcome) = resolve_conflict(&output_dir.join(path), file_entry, options.on_conflict, &stamp)?;
+            let staged_path = write_to.map(|write_to| write_to.strip_prefix(&output_dir).unwrap().to_path_buf());
+
+            // a hard link whose first path was not restored gets its own copy of the content.
+            let first = file_entry.hard_link.as_ref().and_then(|first| staged_files.get(first)).cloned();
+
+            if let (Some(staged_path), EntryKind::File, None) = (&staged_path, &file_entry.kind, &first) {
+                staged_files.insert(path, staged_path.clone());
+            }
+
+            plan.push((path, file_entry, staged_path, first, outcome));
+            Ok(())
+        }));
+
+        // the content of regular files is decrypted and decompressed by `options.jobs` workers at once.
+        let to_write: Vec<_> = plan.iter()
+            .filter_map(|(_, file_entry, staged_path, first, _)| match (staged_path, &file_entry.kind, first) {
+                (Some(staged_path), EntryKind::File, None) => Some((staged_path, *file_entry)),
+                _ => None,
+            })
+            .collect();
+
+        let staged = staged.and_then(|_| utils::run_parallel(&to_write, options.jobs, |(staged_path, file_entry)| {
+            staging.write(staged_path, |file| file_entry.write_content(&blobs_dir, &key, engine.as_ref(), file))
+        }).map(|_| ()));
+
+        let staged = staged.and_then(|_| plan.into_iter().try_for_each(|(path, file_entry, staged_path, first, outcome)| {
+            if let Some(staged_path) = staged_path {
+                match (&file_entry.kind, first) {
+                    (EntryKind::File, Some(first)) => {
+                        staging.link(&first, &staged_path)?;
+                        outcomes.push((path, outcome));
+                        return Ok(());
+                    },
+                    (EntryKind::File, None) => {},
+                    (kind, _) => match staging.make(&staged_path, kind) {
+                        Ok(()) => {},
+                        // only root may create device files.
+                        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                            device_failures += 1;
+                            outcomes.push((path, outcome));
+                            return Ok(());
+                        },
+                        Err(err) => return Err(err.into()),
+                    },
+                }
+
+                // manifests written before metadata was recorded leave the defaults of a new file.
+                if let Some(metadata) = &file_entry.metadata {
+                    let issues = metadata.apply(&staging.dir.join(&staged_path), options.owner.owner_of(metadata))?;
+
+                    owner_failures += usize::from(issues.owner);
+                    xattr_failures += issues.xattrs.len();
                 }
             }
+
+            outcomes.push((path, outcome));
+            Ok(())
+        }));
+
+        if let Err(err) = staged.and_then(|_| staging.commit(&output_dir)) {
+            let left_at = staging.abandon();
+            let message = format!("{err} Partially restored files were left in {:?}.", left_at.display());
+            return Err(SnapError::Restore(message));
         }
-        let mut ent = entry.unwrap().clone();
-        ent.remove_snapshot();
-        registry.add_backup(ent);
-        registry.save_to_file()?;
 
-        fs::remove_file(snapshot_path)?;
+        // children first, so restoring the times of a directory is not undone by restoring one inside it.
+        for (path, dir_entry) in new_dirs.iter().rev() {
+            if let Some(metadata) = &dir_entry.metadata {
+                let issues = metadata.apply(&output_dir.join(path), options.owner.owner_of(metadata))?;
+
+                owner_failures += usize::from(issues.owner);
+                xattr_failures += issues.xattrs.len();
+            }
+        }
+
+        for (path, outcome) in &outcomes {
+            if *outcome != RestoreOutcome::Written {
+                println!("  [{outcome}] {}", path.display());
+            }
+        }
+
+        if !options.filter.is_empty() {
+            println!("Restored {restored} of {total} files.");
+        }
 
+        let count = |f: fn(&RestoreOutcome) -> bool| outcomes.iter().filter(|(_, o)| f(o)).count();
+
+        println!(
+            "Summary: {} written, {} overwritten, {} skipped, {} kept newer, {} renamed",
+            count(|o| *o == RestoreOutcome::Written),
+            count(|o| *o == RestoreOutcome::Overwritten),
+            count(|o| *o == RestoreOutcome::Skipped),
+            count(|o| *o == RestoreOutcome::KeptNewer),
+            count(|o| matches!(o, RestoreOutcome::Renamed(_))),
+        );
+
+        if owner_failures > 0 {
+            println!("Could not restore the owner of {owner_failures} files, restore as root or pass --no-owner.");
+        }
+
+        if xattr_failures > 0 {
+            println!("Could not restore {xattr_failures} extended attributes.");
+        }
+
+        if device_failures > 0 {
+            println!("Could not recreate {device_failures} device files, restore as root to recreate them.");
+        }
+
+        if options.pop {
+            // the restored snapshot is removed the way `snapsafe delete` removes it.
+            let snapshot = Snapshot::from_json_to_snapshot(&snapshot_path, &key)?;
+            let plan = DeletePlan::load(&snapshot_path, &snapshot, &snapshot_dir, &key)?;
+            delete::remove_snapshot(src, &snapshot, &plan, &key)?;
+
+            let mut ent = entry.unwrap().clone();
+            ent.remove_snapshot();
+            registry.add_backup(ent);
+            registry.save_to_file()?;
+        }
     }
     else {
-        return Err(SnapError::Restore("Failed to restore".into()));
+        return Err(SnapError::Restore(format!("Failed to restore: snapshot {selector} does not exist.")));
     }
 
     println!("Restore to {:?} completed.", output_dir.display());
@@ -0,0 +1,91 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use chrono::{DateTime, Utc};
+
+use crate::utils::{self, error::SnapError, snapshot::{EntryKind, FileEntry, Snapshot}};
+
+/// One row of `snapsafe snapshots`.
+#[derive(Debug, PartialEq)]
+pub struct SnapshotSummary {
+    /// file stem of the manifest, it does not change when the backup is copied or other snapshots are removed.
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub total_files: usize,
+    pub updated_files: usize,
+    /// size of the original files.
+    pub logical_size: u64,
+    /// size of the distinct blobs the snapshot references, as stored in `blobs/`.
+    pub stored_size: u64,
+}
+
+impl SnapshotSummary {
+    pub fn new(id: String, snapshot: &Snapshot, blobs_dir: &Path) -> Self {
+        let mut blobs = HashSet::new();
+        let mut stored_size = 0;
+
+        for chunk in snapshot.files.values().flat_map(FileEntry::blobs) {
+            if !blobs.contains(&chunk.hash) {
+                stored_size += fs::metadata(blobs_dir.join(&chunk.hash)).map(|m| m.len()).unwrap_or(0);
+                blobs.insert(chunk.hash);
+            }
+        }
+
+        Self {
+            id,
+            timestamp: snapshot.timestamp,
+            total_files: snapshot.files.values().filter(|entry| entry.kind != EntryKind::Dir).count(),
+            updated_files: snapshot.files.values().filter(|entry| entry.isupdated).count(),
+            logical_size: snapshot.files.values().map(|entry| entry.size).sum(),
+            stored_size,
+        }
+    }
+}
+
+/// Print every snapshot manifest of the backup at `origin`, the most recent one first.
+pub fn list_snapshots(origin: &Path) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+
+    let registry = utils::get_registry();
+    let entry = registry.find_entry_from_dest(origin.to_path_buf());
+
+    let key = if let Some(ent) = entry {
+        utils::unlock_key(origin, &password, Some(ent), false)?
+    } else {
+        let message = "No backup available at path provided";
+        return Err(SnapError::Command(message.into()));
+    };
+
+    let blobs_dir = origin.join("blobs");
+    let snapshot_dir = origin.join("snapshot");
+
+    let snapshots = if snapshot_dir.exists() {
+        utils::list_json_snapshots(&snapshot_dir)?
+    } else {
+        Vec::new()
+    };
+
+    if snapshots.is_empty() {
+        println!("No snapshot stored at {:?}", origin.display());
+        return Ok(());
+    }
+
+    println!("Listing Snapshots of {:?} 📸...", origin.display());
+    for (ix, path) in snapshots.iter().enumerate() {
+        let snapshot = Snapshot::from_json_to_snapshot(path, &key)?;
+        let id = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let summary = SnapshotSummary::new(id, &snapshot, &blobs_dir);
+
+        println!(
+            "- ID: {}\n Number: {}\n Created: {}\n Files: {} ({} updated)\n Size: {} bytes ({} bytes stored)",
+            summary.id,
+            ix + 1,
+            summary.timestamp,
+            summary.total_files,
+            summary.updated_files,
+            summary.logical_size,
+            summary.stored_size,
+        )
+    }
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashMap, fmt, io, path::Path};
+
+use crate::{compress::CompressionEngine, utils::{self, error::SnapError, selector::SnapshotSelector, snapshot::{Chunk, FileEntry, HashingWriter, Snapshot}}};
+
+/// Outcome of checking the blobs a `FileEntry` points to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlobStatus {
+    Ok,
+    Missing,
+    Undecryptable,
+    HashMismatch,
+}
+
+impl fmt::Display for BlobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobStatus::Ok => write!(f, "OK"),
+            BlobStatus::Missing => write!(f, "MISSING"),
+            BlobStatus::Undecryptable => write!(f, "UNDECRYPTABLE"),
+            BlobStatus::HashMismatch => write!(f, "HASH MISMATCH"),
+        }
+    }
+}
+
+/// Check that every blob of `entry` exists in `blobs_dir`, decrypts with `key` and its nonce, decompresses with `engine`
+/// and matches its name, and that the content matches the digest of the entry. Returns the status of the first blob failing.
+pub fn check_entry(entry: &FileEntry, blobs_dir: &Path, key: &[u8], engine: &dyn CompressionEngine) -> BlobStatus {
+    let mut content = HashingWriter::new(io::sink());
+
+    for chunk in entry.blobs() {
+        let status = check_blob(&chunk, blobs_dir, key, engine, &mut content);
+        if status != BlobStatus::Ok {
+            return status;
+        }
+    }
+
+    match &entry.digest {
+        Some(digest) if content.hex_digest() != *digest => BlobStatus::HashMismatch,
+        _ => BlobStatus::Ok,
+    }
+}
+
+fn check_blob(chunk: &Chunk, blobs_dir: &Path, key: &[u8], engine: &dyn CompressionEngine, content: &mut dyn io::Write) -> BlobStatus {
+    if !blobs_dir.join(&chunk.hash).exists() {
+        return BlobStatus::Missing;
+    }
+
+    // segmented blobs are decrypted a segment at a time, so blobs of any size are checked with bounded memory.
+    match chunk.write_content(blobs_dir, key, engine, content) {
+        Ok(_) => BlobStatus::Ok,
+        Err(SnapError::Verify(_)) => BlobStatus::HashMismatch,
+        Err(_) => BlobStatus::Undecryptable,
+    }
+}
+
+/// Verify every file of the snapshot picked by `selector` at `origin`, or of all its snapshots when `selector` is `None`.
+///
+/// Prints one line per file with a blob and fails when any blob is missing, cannot be decrypted or does not match its hash.
+pub fn verify_data(selector: Option<&SnapshotSelector>, origin: &Path) -> Result<(), SnapError> {
+    let password = utils::read_password()?;
+
+    let registry = utils::get_registry();
+    let entry = registry.find_entry_from_dest(origin.to_path_buf());
+
+    let (algorithm, key) = if let Some(ent) = entry {
+        let key = utils::unlock_key(origin, &password, Some(ent), false)?;
+        (ent.compression_algorithm.clone(), key)
+    } else {
+        let message = "No backup available at path provided";
+        return Err(SnapError::Verify(message.into()));
+    };
+
+    let engine = utils::generate_compression_engine(Some(algorithm))?.0;
+
+    let blobs_dir = origin.join("blobs");
+    let snapshot_dir = origin.join("snapshot");
+
+    if !snapshot_dir.exists() {
+        return Err(SnapError::Verify("Target does not contain any snapshot".into()));
+    }
+
+    let snapshots = match selector {
+        Some(selector) => match selector.resolve(&snapshot_dir)? {
+            Some(path) => vec![path],
+            None => return Err(SnapError::Verify(format!("Snapshot {selector} does not exist."))),
+        },
+        None => utils::list_json_snapshots(&snapshot_dir)?,
+    };
+
+    // snapshots share the blobs of unchanged files, check each version of a file only once.
+    let mut checked = HashMap::<String, BlobStatus>::new();
+    let mut total = 0;
+    let mut failed = 0;
+
+    for snapshot_path in &snapshots {
+        let snapshot = Snapshot::from_json_to_snapshot(snapshot_path, &key)?;
+        let name = snapshot_path.file_stem().unwrap_or_default().to_string_lossy();
+
+        // directories, symlinks and special files have no blob to check.
+        let mut files: Vec<_> = snapshot.files.iter().filter(|(_, entry)| entry.has_blob()).collect();
+
+        println!("Verifying snapshot {name} ({} files)", files.len());
+
+        files.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (path, file_entry) in files {
+            let status = checked.entry(file_entry.hash.clone())
+                .or_insert_with(|| check_entry(file_entry, &blobs_dir, &key, engine.as_ref()))
+                .clone();
+
+            total += 1;
+            if status != BlobStatus::Ok {
+                failed += 1;
+            }
+
+            println!("  [{status}] {}", path.display());
+        }
+    }
+
+    println!("Verified {total} files in {} snapshots: {} ok, {failed} failed", snapshots.len(), total - failed);
+
+    if failed > 0 {
+        return Err(SnapError::Verify(format!("{failed} of {total} files failed verification.")));
+    }
+
+    Ok(())
+}
@@ -1,19 +1,43 @@
-use clap::{Parser, Subcommand};
-use std::path::Path;
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
 
-use crate::{actions, utils::{self, error::SnapError}};
+use crate::{actions::{self, ls::LsOptions, restore::{ConflictPolicy, IdMapping, OwnerOptions, RestoreFilter, RestoreOptions}}, utils::{self, error::SnapError, rules::BackupRules, selector::SnapshotSelector}};
 
 #[derive(Parser)]
 #[command(name = "snapshot", version = "1.0", about = "A secure backup and restore tool.", after_help = "Strict password enforcement:\n\
-             - Backups are bound to the password used during creation.\n\
+             - Backups are bound to the passwords stored in their key slots, the first one being the password used during creation.\n\
              - If a different password is provided for the same destination, the operation will fail.\n\
              - This is to prevent accidental overwrite or mismatched encryption keys.\n\
-             - To change the password in the future, use a planned `snapsafe rekey` command.")]
+             - To add, remove or change a password, use the `snapsafe key` command.")]
 pub struct CLI {
     #[command(subcommand)]
     command: Commands
 }
 
+/// Options selecting the snapshot a command works on, the latest snapshot when none is given.
+#[derive(Args, Debug)]
+pub struct SnapshotArgs {
+    /// snapshot to use: an id shown by `snapsafe snapshots`, `latest`, or `latest~N` for the Nth snapshot before the latest
+    #[arg(short = 's', long, required = false, conflicts_with_all = ["at", "number"])]
+    snapshot: Option<SnapshotSelector>,
+    /// use the newest snapshot taken at or before this UTC date and time, e.g. "2024-05-01 18:30:00" or "2024-05-01"
+    #[arg(long, required = false, value_parser = SnapshotSelector::at, conflicts_with = "number")]
+    at: Option<SnapshotSelector>,
+    /// position of the snapshot where 1 is the latest, same as `--snapshot latest~<number - 1>`
+    #[arg(short = 'n', long, required = false, value_parser = clap::value_parser!(u64).range(1..))]
+    number: Option<u64>,
+}
+
+impl SnapshotArgs {
+    /// The snapshot selected by the options, `None` when none of them was given.
+    fn selector(self) -> Option<SnapshotSelector> {
+        match self.number {
+            Some(n) => Some(SnapshotSelector::Latest((n - 1) as usize)),
+            None => self.snapshot.or(self.at),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// generate the process to build a config file for your system.
@@ -31,28 +55,175 @@ pub enum Commands {
         #[arg(short = 'd', long = "dest", required = true)]
         target: String,
         #[arg(short = 'c', long = "comp", required = false)]
-        comp: Option<String>
+        comp: Option<String>,
+        /// skip files matching this gitignore pattern, e.g. "target/" or "*.log", can be repeated
+        #[arg(long, required = false)]
+        exclude: Vec<String>,
+        /// back up files matching this gitignore pattern even when another rule skips them, can be repeated
+        #[arg(long, required = false)]
+        include: Vec<String>,
+        /// also skip the files ignored by `.gitignore` files in the source
+        #[arg(long)]
+        gitignore: bool,
+        /// back up directories marked with a `CACHEDIR.TAG`, which are skipped by default
+        #[arg(long)]
+        include_caches: bool,
+        /// back up the files and directories symlinks point to instead of the symlinks themselves
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// report the files that would be stored, reused and pruned without writing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// number of files read, compressed and encrypted at once, one per CPU by default
+        #[arg(short = 'j', long, default_value_t = 0, hide_default_value = true)]
+        jobs: usize,
+        /// read and hash every file, even the ones whose size, times and inode show they did not change
+        #[arg(long)]
+        force_rehash: bool,
     },
     /// use this to restore backup at a certain origin to an output directory: `snapsafe restore --help` for usage info
     Restore {
-        #[arg(short = 'n', long, required = false)]
-        number: Option<u8>,
+        #[command(flatten)]
+        snapshot: SnapshotArgs,
         #[arg(long, required = true)]
         origin: String,
         #[arg(short = 'o', long = "output", required = true)]
         target: String,
+        /// remove the restored snapshot from the backup once it has been restored
+        #[arg(long, conflicts_with_all = ["paths", "include", "exclude"])]
+        pop: bool,
+        /// only restore files matching this glob pattern, can be repeated
+        #[arg(long, required = false)]
+        include: Vec<String>,
+        /// do not restore files matching this glob pattern, can be repeated
+        #[arg(long, required = false)]
+        exclude: Vec<String>,
+        /// what to do with files that already exist in the output directory: overwrite, skip, keep-newer, rename or fail
+        #[arg(long = "on-conflict", required = false, default_value = "overwrite")]
+        on_conflict: ConflictPolicy,
+        /// leave the restored files owned by the user running the restore instead of their recorded owner
+        #[arg(long, conflicts_with_all = ["map_uid", "map_gid"])]
+        no_owner: bool,
+        /// give files recorded with uid OLD the uid NEW, written OLD:NEW, can be repeated
+        #[arg(long, required = false)]
+        map_uid: Vec<IdMapping>,
+        /// give files recorded with gid OLD the gid NEW, written OLD:NEW, can be repeated
+        #[arg(long, required = false)]
+        map_gid: Vec<IdMapping>,
+        /// number of files decrypted and decompressed at once, one per CPU by default
+        #[arg(short = 'j', long, default_value_t = 0, hide_default_value = true)]
+        jobs: usize,
+        /// only restore these files or directories, relative to the backed up directory
+        paths: Vec<String>,
     },
-    /// use this to delete the latest backup or the nth backup where 1 is the latest: `snapsafe delete --help` for usage info
+    /// use this to delete the latest snapshot or the one selected with `--snapshot`, `--at` or `--number`: `snapsafe delete --help` for usage info
     Delete{
-        #[arg(short = 'n', long, required = false)]
-        number: Option<u8>,
+        #[command(flatten)]
+        snapshot: SnapshotArgs,
         #[arg(short = 'o', long, required = true)]
         origin: String,
         #[arg(long)]
-        force: bool
+        force: bool,
+        /// report the manifest and blobs that would be removed or kept without deleting anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// use this to list all backups a user has made: `snapsafe list`
     List, 
+    /// use this to list the snapshots stored in a backup: `snapsafe snapshots --origin <dest>`
+    Snapshots {
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+    },
+    /// use this to browse the files of a snapshot without restoring it: `snapsafe ls --help` for usage info
+    Ls {
+        #[command(flatten)]
+        snapshot: SnapshotArgs,
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+        /// directory inside the snapshot to list, the snapshot root by default
+        path: Option<String>,
+        /// list every file below the directory as a tree
+        #[arg(short = 'r', long)]
+        recursive: bool,
+        /// show size, modified time and version hash of each file
+        #[arg(short = 'l', long)]
+        long: bool,
+        /// only show files whose relative path matches this glob pattern, e.g. "*.log"
+        #[arg(short = 'g', long, required = false)]
+        glob: Option<String>,
+    },
+    /// use this to write a single file of a snapshot to stdout: `snapsafe cat --help` for usage info
+    Cat {
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+        #[command(flatten)]
+        snapshot: SnapshotArgs,
+        /// path of the file relative to the backed up directory
+        path: String,
+    },
+    /// use this to compare two snapshots of a backup: `snapsafe diff --help` for usage info
+    Diff {
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+        /// older snapshot: an id, `latest` or `latest~N`, `latest~1` by default
+        #[arg(long, required = false, conflicts_with = "from_at")]
+        from: Option<SnapshotSelector>,
+        /// use the newest snapshot taken at or before this UTC date and time as the older snapshot
+        #[arg(long, required = false, value_parser = SnapshotSelector::at)]
+        from_at: Option<SnapshotSelector>,
+        /// newer snapshot: an id, `latest` or `latest~N`, `latest` by default
+        #[arg(long, required = false, conflicts_with = "to_at")]
+        to: Option<SnapshotSelector>,
+        /// use the newest snapshot taken at or before this UTC date and time as the newer snapshot
+        #[arg(long, required = false, value_parser = SnapshotSelector::at)]
+        to_at: Option<SnapshotSelector>,
+        /// relative path of a single file to compare line by line between the two snapshots
+        #[arg(short = 'f', long, required = false)]
+        file: Option<String>,
+    },
+    /// use this to check that the blobs of one snapshot, or of all snapshots, decrypt and match their hash: `snapsafe verify --help` for usage info
+    Verify {
+        // all snapshots are verified when no snapshot is selected
+        #[command(flatten)]
+        snapshot: SnapshotArgs,
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+    },
+    /// use this to manage the passwords that unlock a backup: `snapsafe key --help` for usage info
+    Key {
+        #[command(subcommand)]
+        action: KeyCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeyCommands {
+    /// add another password that unlocks the backup at origin
+    Add {
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+    },
+    /// remove the key slot of the password entered, or the slot given with `--slot`
+    Remove {
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+        #[arg(long, required = false)]
+        slot: Option<u32>,
+    },
+    /// replace the password entered with a new one
+    ///
+    /// A backup made before key slots keeps its `key_salt`: its master key is derived from the first password,
+    /// so that password still derives the master key after the change.
+    Change {
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+    },
+    /// list the key slots of the backup at origin
+    List {
+        #[arg(short = 'o', long, required = true)]
+        origin: String,
+    },
 }
 
 
@@ -63,7 +234,7 @@ pub fn entry() -> Result<(), SnapError> {
         Commands::Config { global: _, local } => {
             let _ = actions::config(local)?;
         },
-        Commands::Backup { source, target,  comp} => {
+        Commands::Backup { source, target, comp, exclude, include, gitignore, include_caches, follow_symlinks, dry_run, jobs, force_rehash } => {
             let src = Path::new(&source);
             let dest = Path::new(&target);
 
@@ -74,9 +245,11 @@ pub fn entry() -> Result<(), SnapError> {
 
             let config = Some(utils::get_config());
 
-            actions::backup(src, dest, comp, config)?;
+            let rules = BackupRules { follow_symlinks, jobs, force_rehash, ..BackupRules::new(exclude, include, gitignore, !include_caches) };
+
+            actions::backup(src, dest, comp, config, &rules, dry_run)?;
         },
-        Commands::Restore { number, origin, target } => {
+        Commands::Restore { snapshot, origin, target, pop, include, exclude, on_conflict, no_owner, map_uid, map_gid, jobs, paths } => {
             let src = Path::new(&origin);
             let output_dir = Path::new(&target);
 
@@ -86,9 +259,14 @@ pub fn entry() -> Result<(), SnapError> {
                 return Err(err);
             }
 
-            actions::restore(number.unwrap_or(1), src, output_dir)?;
+            let paths = paths.into_iter().map(PathBuf::from).collect();
+            let filter = RestoreFilter::new(paths, &include, &exclude)?;
+            let owner = OwnerOptions::new(no_owner, &map_uid, &map_gid);
+            let options = RestoreOptions { pop, filter, on_conflict, owner, jobs };
+
+            actions::restore(&snapshot.selector().unwrap_or_default(), src, output_dir, &options)?;
         },
-        Commands::Delete { number, origin, force} => {
+        Commands::Delete { snapshot, origin, force, dry_run } => {
             let target = Path::new(&origin);
             
             if !target.try_exists().unwrap_or(false) {
@@ -97,10 +275,91 @@ pub fn entry() -> Result<(), SnapError> {
                 return Err(err);
             }
 
-            actions::delete(number.unwrap_or(1), target, force)?;
+            actions::delete(&snapshot.selector().unwrap_or_default(), target, force, dry_run)?;
         },
         Commands::List => {
             let _ = actions::list();
+        },
+        Commands::Snapshots { origin } => {
+            let target = Path::new(&origin);
+
+            if !target.try_exists().unwrap_or(false) {
+                let message = "Target Directory with expected backed up data does not exist";
+                let err = SnapError::Command(message.into());
+                return Err(err);
+            }
+
+            actions::snapshots(target)?;
+        },
+        Commands::Ls { snapshot, origin, path, recursive, long, glob } => {
+            let target = Path::new(&origin);
+
+            if !target.try_exists().unwrap_or(false) {
+                let message = "Target Directory with expected backed up data does not exist";
+                let err = SnapError::Command(message.into());
+                return Err(err);
+            }
+
+            let options = LsOptions { prefix: path.map(PathBuf::from), recursive, long, glob };
+            actions::ls(&snapshot.selector().unwrap_or_default(), target, &options)?;
+        },
+        Commands::Cat { origin, snapshot, path } => {
+            let target = Path::new(&origin);
+
+            if !target.try_exists().unwrap_or(false) {
+                let message = "Target Directory with expected backed up data does not exist";
+                let err = SnapError::Command(message.into());
+                return Err(err);
+            }
+
+            actions::cat(&snapshot.selector().unwrap_or_default(), target, Path::new(&path))?;
+        },
+        Commands::Diff { origin, from, from_at, to, to_at, file } => {
+            let target = Path::new(&origin);
+
+            if !target.try_exists().unwrap_or(false) {
+                let message = "Target Directory with expected backed up data does not exist";
+                let err = SnapError::Command(message.into());
+                return Err(err);
+            }
+
+            let from = from.or(from_at).unwrap_or(SnapshotSelector::Latest(1));
+            let to = to.or(to_at).unwrap_or_default();
+
+            actions::diff(&from, &to, target, file.map(PathBuf::from))?;
+        },
+        Commands::Verify { snapshot, origin } => {
+            let target = Path::new(&origin);
+
+            if !target.try_exists().unwrap_or(false) {
+                let message = "Target Directory with expected backed up data does not exist";
+                let err = SnapError::Command(message.into());
+                return Err(err);
+            }
+
+            actions::verify(snapshot.selector().as_ref(), target)?;
+        },
+        Commands::Key { action } => {
+            let origin = match &action {
+                KeyCommands::Add { origin } 
+                | KeyCommands::Remove { origin, .. } 
+                | KeyCommands::Change { origin } 
+                | KeyCommands::List { origin } => origin.clone(),
+            };
+            let target = Path::new(&origin);
+
+            if !target.try_exists().unwrap_or(false) {
+                let message = "Target Directory with expected backed up data does not exist";
+                let err = SnapError::Command(message.into());
+                return Err(err);
+            }
+
+            match action {
+                KeyCommands::Add { .. } => actions::key_add(target)?,
+                KeyCommands::Remove { slot, .. } => actions::key_remove(target, slot)?,
+                KeyCommands::Change { .. } => actions::key_change(target)?,
+                KeyCommands::List { .. } => actions::key_list(target)?,
+            }
         }
     }
     Ok(())
@@ -1,4 +1,4 @@
-use std::{io::{Read, Write}, str::FromStr};
+use std::{io::{self, Read, Write}, str::FromStr};
 
 use brotli2::{read::BrotliDecoder, write::BrotliEncoder};
 use flate2::{read::{GzDecoder, ZlibDecoder}, write::{GzEncoder, ZlibEncoder}, Compression};
@@ -20,9 +20,25 @@ pub enum CompressionType {
     Lzma,
 }
 
-pub trait CompressionEngine {
-    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError>;
-    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError>;
+/// Compression over streams, so content of any size is compressed with bounded memory.
+/// Engines are shared by the worker threads of a backup or restore.
+pub trait CompressionEngine: Send + Sync {
+    /// Compress everything `reader` yields into `writer`, returns the number of bytes read.
+    fn compress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError>;
+    /// Decompress everything `reader` yields into `writer`, returns the number of bytes written.
+    fn decompress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError>;
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
+        let mut compressed = Vec::new();
+        self.compress_stream(&mut &data[..], &mut compressed)?;
+        Ok(compressed)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
+        let mut decompressed = Vec::new();
+        self.decompress_stream(&mut &data[..], &mut decompressed)?;
+        Ok(decompressed)
+    }
 }
 
 impl FromStr for CompressionType {
@@ -44,95 +60,90 @@ impl FromStr for CompressionType {
 pub struct NullEngine;
 
 impl CompressionEngine for NullEngine {
-    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        Ok(data.to_vec())
+    fn compress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        Ok(io::copy(reader, writer)?)
     }
 
-    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        Ok(data.to_vec())
+    fn decompress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        Ok(io::copy(reader, writer)?)
     }
 }
 
 pub struct GzipEngine { pub level: u32 }
 
 impl CompressionEngine for GzipEngine {
-    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.level));
-        encoder.write_all(data)?;
-        Ok(encoder.finish()?)
+    fn compress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut encoder = GzEncoder::new(writer, Compression::new(self.level));
+        let read = io::copy(reader, &mut encoder)?;
+        encoder.finish()?;
+        Ok(read)
     }
 
-    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut decoder = GzDecoder::new(data);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed)?;
-        Ok(decompressed)
+    fn decompress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut decoder = GzDecoder::new(reader);
+        Ok(io::copy(&mut decoder, writer)?)
     }
 }
 pub struct BrotliEngine { pub level: u32 }
 
-impl CompressionEngine for BrotliEngine{
-    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut encoder = BrotliEncoder::new(Vec::new(), self.level);
-        encoder.write_all(data)?;
-        Ok(encoder.finish()?)
+impl CompressionEngine for BrotliEngine {
+    fn compress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut encoder = BrotliEncoder::new(writer, self.level);
+        let read = io::copy(reader, &mut encoder)?;
+        encoder.finish()?;
+        Ok(read)
     }
 
-    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut decoder = BrotliDecoder::new(data);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed)?;
-        Ok(decompressed)
+    fn decompress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut decoder = BrotliDecoder::new(reader);
+        Ok(io::copy(&mut decoder, writer)?)
     }
 }
 
 pub struct ZlibEngine { pub level: u32 }
 
 impl CompressionEngine for ZlibEngine {
-    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(self.level));
-        encoder.write_all(data)?;
-        Ok(encoder.finish()?)
+    fn compress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut encoder = ZlibEncoder::new(writer, Compression::new(self.level));
+        let read = io::copy(reader, &mut encoder)?;
+        encoder.finish()?;
+        Ok(read)
     }
 
-    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut decoder = ZlibDecoder::new(data);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed)?;
-        Ok(decompressed)
+    fn decompress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut decoder = ZlibDecoder::new(reader);
+        Ok(io::copy(&mut decoder, writer)?)
     }
 }
 
 pub struct ZstdEngine { pub level: i32 }
 
 impl CompressionEngine for ZstdEngine {
-    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut encoder = ZstdEncoder::new(Vec::new(), self.level)?;
-        encoder.write_all(data)?;
-        Ok(encoder.finish()?)
+    fn compress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut encoder = ZstdEncoder::new(writer, self.level)?;
+        let read = io::copy(reader, &mut encoder)?;
+        encoder.finish()?;
+        Ok(read)
     }
 
-    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut decoder = ZstdDecoder::new(data)?;
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed)?;
-        Ok(decompressed)
+    fn decompress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut decoder = ZstdDecoder::new(reader)?;
+        Ok(io::copy(&mut decoder, writer)?)
     }
 }
 
 pub struct LzmaEngine { pub level: u32 }
 
 impl CompressionEngine for LzmaEngine {
-    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut encoder = LzmaEncoder::new(Vec::new(), self.level);
-        encoder.write_all(data)?;
-        Ok(encoder.finish()?)
+    fn compress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut encoder = LzmaEncoder::new(writer, self.level);
+        let read = io::copy(reader, &mut encoder)?;
+        encoder.finish()?;
+        Ok(read)
     }
 
-    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, SnapError> {
-        let mut decoder = LzmaDecoder::new(data);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed)?;
-        Ok(decompressed)
+    fn decompress_stream(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut decoder = LzmaDecoder::new(reader);
+        Ok(io::copy(&mut decoder, writer)?)
     }
 }
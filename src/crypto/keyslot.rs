@@ -0,0 +1,150 @@
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{self, password::PasswordError};
+
+/// struct `KeySlot` holds the repository master key encrypted with a key derived from one password.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeySlot {
+    pub id: u32,
+    pub created: DateTime<Utc>,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    wrapped_key: Vec<u8>,
+}
+
+/// struct `KeyStore` is the list of key slots of a backup, saved as `keys.json` in the backup destination.
+///
+/// Every slot wraps the same random master key, the key every blob is encrypted with.
+/// Adding, removing or changing a password only rewrites the slots, never the blobs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct KeyStore {
+    pub slots: Vec<KeySlot>,
+    /// Format version every manifest of the backup is written in, 0 while some manifests may still be plaintext.
+    #[serde(default)]
+    manifest_version: u32,
+    /// hex MAC of `manifest_version` keyed from the master key, see `crypto::manifest_version_mac`.
+    #[serde(default)]
+    manifest_mac: Option<String>,
+}
+
+impl KeySlot {
+    fn new(id: u32, master_key: &[u8; 32], password: &str) -> Self {
+        let salt: [u8; 16] = rand::random();
+        let kek = crypto::derive_key(password, &salt);
+        let (wrapped_key, nonce) = crypto::encrypt_file_bytes(master_key, &kek);
+
+        Self { id, created: Utc::now(), salt, nonce, wrapped_key }
+    }
+
+    /// Decrypt the master key with `password`. AES-GCM authentication fails for any other password.
+    fn open(&self, password: &str) -> Option<[u8; 32]> {
+        let kek = crypto::derive_key(password, &self.salt);
+        let master_key = crypto::decrypt_file_bytes(&self.wrapped_key, &kek, &self.nonce).ok()?;
+
+        master_key.try_into().ok()
+    }
+}
+
+impl KeyStore {
+    /// Build a key store with a single slot wrapping `master_key` with `password`.
+    pub fn new(master_key: &[u8; 32], password: &str) -> Self {
+        Self { slots: vec![KeySlot::new(1, master_key, password)], manifest_version: 0, manifest_mac: None }
+    }
+
+    /// Manifest format version recorded for the backup, 0 unless its MAC checks out with `master_key`.
+    pub fn manifest_version(&self, master_key: &[u8]) -> u32 {
+        let Some(tag) = self.manifest_mac.as_deref().and_then(|tag| hex::decode(tag).ok()) else {
+            return 0;
+        };
+
+        match crypto::manifest_version_mac(master_key, self.manifest_version).verify_slice(&tag) {
+            Ok(()) => self.manifest_version,
+            Err(_) => 0,
+        }
+    }
+
+    /// Record that every manifest of the backup is written in format `version`, authenticated with `master_key`.
+    pub fn set_manifest_version(&mut self, master_key: &[u8], version: u32) {
+        let mac = crypto::manifest_version_mac(master_key, version);
+
+        self.manifest_version = version;
+        self.manifest_mac = Some(hex::encode(mac.finalize().into_bytes()));
+    }
+
+    pub fn path(dest: &Path) -> PathBuf {
+        dest.join("keys.json")
+    }
+
+    pub fn exists(dest: &Path) -> bool {
+        Self::path(dest).exists()
+    }
+
+    /// Load the key store of the backup at `dest`, `None` when the backup has none yet.
+    pub fn load(dest: &Path) -> io::Result<Option<Self>> {
+        let path = Self::path(dest);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read(&path)?;
+        let store = serde_json::from_slice::<KeyStore>(&content)?;
+
+        Ok(Some(store))
+    }
+
+    /// Save the key store as `<dest>/keys.json`. It is written to a temporary file that replaces `keys.json`
+    /// once synced to disk, so a crash never leaves the backup without a readable key store.
+    pub fn save(&self, dest: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&self)?;
+        let path = Self::path(dest);
+        let temp = path.with_extension("json.tmp");
+
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(&temp, &path)?;
+        fs::File::open(dest)?.sync_all()
+    }
+
+    /// Try `password` against every slot and return the id of the slot it opens along with the master key.
+    pub fn unlock(&self, password: &str) -> Result<(u32, [u8; 32]), PasswordError> {
+        self.slots.iter()
+            .find_map(|slot| slot.open(password).map(|key| (slot.id, key)))
+            .ok_or(PasswordError::IncorrectPassword)
+    }
+
+    /// Wrap `master_key` with `password` in a new slot and return the id of that slot.
+    pub fn add_slot(&mut self, master_key: &[u8; 32], password: &str) -> u32 {
+        let id = self.slots.iter().map(|slot| slot.id).max().unwrap_or(0) + 1;
+        self.slots.push(KeySlot::new(id, master_key, password));
+        id
+    }
+
+    /// Replace the slot `id` with one wrapping `master_key` with `password`, keeping its id.
+    pub fn replace_slot(&mut self, id: u32, master_key: &[u8; 32], password: &str) -> bool {
+        match self.slots.iter_mut().find(|slot| slot.id == id) {
+            Some(slot) => {
+                *slot = KeySlot::new(id, master_key, password);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Remove the slot `id`. The last remaining slot is never removed since the backup would become unreadable.
+    pub fn remove_slot(&mut self, id: u32) -> bool {
+        if self.slots.len() <= 1 {
+            return false;
+        }
+
+        let count = self.slots.len();
+        self.slots.retain(|slot| slot.id != id);
+        self.slots.len() < count
+    }
+}
@@ -2,8 +2,12 @@ use std::error::Error;
 
 use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
 use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
+pub mod keyslot;
 pub mod password;
+pub mod stream;
 
 pub fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
     let argon2 = Argon2::default();
@@ -20,9 +24,32 @@ pub fn encrypt_file_bytes(data: &[u8], key: &[u8]) -> (Vec<u8>, [u8; 12]) {
     (ciphertext, nonce_bytes)
 }
 
-pub fn decrypt_file_bytes(ciphertext: &[u8], key: &[u8], nonce_bytes: &[u8; 12]) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn decrypt_file_bytes(ciphertext: &[u8], key: &[u8], nonce_bytes: &[u8; 12]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
     let cipher = Aes256Gcm::new_from_slice(key).unwrap();
     let nonce = Nonce::from_slice(nonce_bytes);
 
     cipher.decrypt(nonce, ciphertext).map_err(|err| format!("Decryption failed: {err}").into())
 }
+
+/// MAC naming blobs after their content, keyed with a key derived from the repository key `key`.
+///
+/// Names stay the same for the same content, so blobs are still deduplicated, but tell nothing about it
+/// to whoever reads the destination without the key.
+pub fn blob_mac(key: &[u8]) -> Hmac<Sha256> {
+    derived_mac(key, b"snapsafe blob names")
+}
+
+/// MAC of the manifest format version `version` recorded in the key store, keyed with a key derived from the
+/// repository key `key`, so the recorded version cannot be lowered by whoever lacks the key.
+pub fn manifest_version_mac(key: &[u8], version: u32) -> Hmac<Sha256> {
+    let mut mac = derived_mac(key, b"snapsafe manifest version");
+    mac.update(&version.to_be_bytes());
+    mac
+}
+
+fn derived_mac(key: &[u8], label: &[u8]) -> Hmac<Sha256> {
+    let mut derivation = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    derivation.update(label);
+
+    <Hmac<Sha256> as Mac>::new_from_slice(&derivation.finalize().into_bytes()).unwrap()
+}
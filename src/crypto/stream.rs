@@ -0,0 +1,169 @@
+use std::io::{self, Read, Write};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+
+/// Bytes of plaintext in every segment of a blob but the last one, which may be shorter.
+pub const SEGMENT_SIZE: usize = 65_536;
+
+/// Bytes the AES-GCM tag adds to every segment.
+const TAG_SIZE: usize = 16;
+
+/// struct `EncryptWriter` encrypts what is written to it with AES-256-GCM in segments of `SEGMENT_SIZE` bytes,
+/// so content of any size is encrypted and later decrypted with bounded memory.
+///
+/// Every segment is sealed on its own with a nonce derived from the blob nonce, its index and whether it is the last one,
+/// so segments cannot be reordered, dropped or cut off without `DecryptReader` failing.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    nonce: [u8; 12],
+    index: u32,
+    buffer: Vec<u8>,
+}
+
+/// struct `DecryptReader` reads the plaintext of a blob written by `EncryptWriter`, one authenticated segment at a time.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    nonce: [u8; 12],
+    index: u32,
+    /// ciphertext read ahead, a full segment and one byte tell whether another segment follows.
+    ciphertext: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+/// Encrypt `data` in segments with a new random nonce, returns the ciphertext and the nonce.
+pub fn encrypt_segmented(data: &[u8], key: &[u8]) -> (Vec<u8>, [u8; 12]) {
+    let nonce: [u8; 12] = rand::random();
+
+    let mut writer = EncryptWriter::new(Vec::with_capacity(data.len() + TAG_SIZE), key, nonce);
+    writer.write_all(data).unwrap();
+
+    (writer.finish().unwrap(), nonce)
+}
+
+/// Nonce of segment `index`, the blob nonce with the index and the last segment flag xored into its last 5 bytes.
+fn segment_nonce(nonce: &[u8; 12], index: u32, last: bool) -> [u8; 12] {
+    let mut segment = *nonce;
+
+    for (byte, value) in segment[7..11].iter_mut().zip(index.to_be_bytes()) {
+        *byte ^= value;
+    }
+    segment[11] ^= u8::from(last);
+
+    segment
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(inner: W, key: &[u8], nonce: [u8; 12]) -> Self {
+        Self {
+            inner,
+            cipher: Aes256Gcm::new_from_slice(key).unwrap(),
+            nonce,
+            index: 0,
+            buffer: Vec::with_capacity(SEGMENT_SIZE),
+        }
+    }
+
+    /// Encrypt the last segment, empty when nothing was written since the previous one, and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let nonce = segment_nonce(&self.nonce, self.index, last);
+        let ciphertext = self.cipher.encrypt(Nonce::from_slice(&nonce), self.buffer.as_slice())
+            .map_err(|err| io::Error::other(format!("Encryption failed: {err}")))?;
+
+        self.inner.write_all(&ciphertext)?;
+        self.buffer.clear();
+        self.index = self.index.checked_add(1).ok_or_else(|| io::Error::other("Too many segments in one blob"))?;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a full segment is only sealed once more data comes, it is the last one otherwise.
+        if self.buffer.len() == SEGMENT_SIZE && !buf.is_empty() {
+            self.seal(false)?;
+        }
+
+        let len = buf.len().min(SEGMENT_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(inner: R, key: &[u8], nonce: [u8; 12]) -> Self {
+        Self {
+            inner,
+            cipher: Aes256Gcm::new_from_slice(key).unwrap(),
+            nonce,
+            index: 0,
+            ciphertext: Vec::with_capacity(SEGMENT_SIZE + TAG_SIZE + 1),
+            plaintext: Vec::new(),
+            position: 0,
+            done: false,
+        }
+    }
+
+    fn next_segment(&mut self) -> io::Result<()> {
+        let full = SEGMENT_SIZE + TAG_SIZE;
+
+        while self.ciphertext.len() <= full {
+            let start = self.ciphertext.len();
+            self.ciphertext.resize(full + 1, 0);
+
+            let read = self.inner.read(&mut self.ciphertext[start..])?;
+            self.ciphertext.truncate(start + read);
+
+            if read == 0 {
+                break;
+            }
+        }
+
+        let last = self.ciphertext.len() <= full;
+        let len = self.ciphertext.len().min(full);
+        let nonce = segment_nonce(&self.nonce, self.index, last);
+
+        self.plaintext = self.cipher.decrypt(Nonce::from_slice(&nonce), &self.ciphertext[..len])
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Decryption of segment {} failed: {err}", self.index)))?;
+
+        self.ciphertext.drain(..len);
+        self.position = 0;
+        self.index += 1;
+        self.done = last;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.done {
+                return Ok(0);
+            }
+
+            self.next_segment()?;
+        }
+
+        let len = buf.len().min(self.plaintext.len() - self.position);
+        buf[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
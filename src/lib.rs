@@ -4,8 +4,19 @@ pub mod compress;
 pub mod utils;
 pub mod crypto;
 
+/// A regular file entry stored in the blob `hash`, for tests to adjust with struct update syntax.
+#[cfg(test)]
+fn file_entry(hash: &str) -> utils::snapshot::FileEntry {
+    utils::snapshot::FileEntry {
+        hash: hash.into(), nonce: [0u8; 12], modified: std::time::SystemTime::now(), isupdated: true, size: 0,
+        metadata: None, kind: Default::default(), hard_link: None, chunks: None, digest: None, change_key: None,
+    }
+}
+
 #[cfg(test)]
 mod crypto_tests {
+    use std::io::Read;
+
     use crate::crypto::*;
 
     #[test]
@@ -50,49 +61,316 @@ mod crypto_tests {
         let (encrypted, nonce) = encrypt_file_bytes(data, &key1);
         let _ = decrypt_file_bytes(&encrypted, &key2, &nonce); // should panic
     }
+
+    #[test]
+    fn test_blob_names_depend_on_the_key_only() {
+        use hmac::Mac;
+        use sha2::{Digest, Sha256};
+
+        let name = |key: &[u8], data: &[u8]| blob_mac(key).chain_update(data).finalize().into_bytes().to_vec();
+        let data = b"known file content";
+
+        assert_eq!(name(&[1u8; 32], data), name(&[1u8; 32], data));
+        assert_ne!(name(&[1u8; 32], data), name(&[2u8; 32], data));
+        assert_ne!(name(&[1u8; 32], data), Sha256::digest(data).to_vec());
+    }
+
+    fn decrypt_segmented(ciphertext: &[u8], key: &[u8], nonce: [u8; 12]) -> std::io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        stream::DecryptReader::new(ciphertext, key, nonce).read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_segmented_encrypt_decrypt_cycle() {
+        let key = [7u8; 32];
+
+        for len in [0, 10, stream::SEGMENT_SIZE, 3 * stream::SEGMENT_SIZE + 5] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let (encrypted, nonce) = stream::encrypt_segmented(&data, &key);
+
+            assert_eq!(decrypt_segmented(&encrypted, &key, nonce).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_segmented_decrypt_rejects_truncated_or_tampered_blobs() {
+        let key = [7u8; 32];
+        let data = vec![1u8; 2 * stream::SEGMENT_SIZE + 100];
+        let (encrypted, nonce) = stream::encrypt_segmented(&data, &key);
+
+        // dropping the last segment leaves a stream ending on a segment not sealed as the last one.
+        let truncated = &encrypted[..stream::SEGMENT_SIZE + 16];
+        assert!(decrypt_segmented(truncated, &key, nonce).is_err());
+
+        let mut tampered = encrypted.clone();
+        tampered[stream::SEGMENT_SIZE + 20] ^= 1;
+        assert!(decrypt_segmented(&tampered, &key, nonce).is_err());
+
+        assert!(decrypt_segmented(&encrypted, &[8u8; 32], nonce).is_err());
+    }
+}
+
+#[cfg(test)]
+mod compress_tests {
+    use crate::compress::build_engine;
+
+    #[test]
+    fn test_stream_compression_round_trip() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+
+        for algorithm in ["none", "gzip", "zlib", "brotli", "zstd", "lzma"] {
+            let engine = build_engine(algorithm.into()).unwrap();
+
+            let mut compressed = Vec::new();
+            let read = engine.compress_stream(&mut data.as_slice(), &mut compressed).unwrap();
+
+            let mut decompressed = Vec::new();
+            let written = engine.decompress_stream(&mut compressed.as_slice(), &mut decompressed).unwrap();
+
+            assert_eq!((read, written), (data.len() as u64, data.len() as u64), "{algorithm}");
+            assert_eq!(decompressed, data, "{algorithm}");
+            assert_eq!(engine.decompress(&engine.compress(&data).unwrap()).unwrap(), data, "{algorithm}");
+        }
+    }
 }
 
 #[cfg(test)]
 mod gc_tests {
-    use std::{fs::File, path::PathBuf};
+    use std::{collections::{HashMap, HashSet}, fs::{self, File}, os::unix::fs::PermissionsExt, path::{Path, PathBuf}};
 
+    use chrono::Utc;
     use tempfile::tempdir;
 
-    use crate::utils::gc::{GarbageCollector, SnapshotReference};
+    use crate::{file_entry, crypto::keyslot::KeyStore, utils::{self, gc::GarbageCollector, journal::Journal, rules::BackupRules, snapshot::{Chunk, FileEntry, Snapshot, MANIFEST_VERSION}}};
+
+    const KEY: [u8; 32] = [7u8; 32];
 
+    /// Write a manifest holding `path` at version `hash`, stored in `blobs`, and create those blobs.
+    fn write_version(dest: &Path, path: &str, hash: &str, blobs: &[&str]) -> (PathBuf, Snapshot) {
+        let chunks = blobs.iter().map(|blob| {
+            File::create(dest.join("blobs").join(blob)).unwrap();
+            Chunk { hash: blob.to_string(), nonce: [0u8; 12], size: 1, segmented: false, naming: Default::default() }
+        }).collect();
+
+        let entry = FileEntry { size: 1, chunks: Some(chunks), ..file_entry(hash) };
+        let snapshot = Snapshot { timestamp: Utc::now(), files: HashMap::from([(PathBuf::from(path), entry)]), rules: Default::default() };
+
+        let manifest = dest.join("snapshot").join(format!("{hash}.json"));
+        fs::create_dir_all(dest.join("snapshot")).unwrap();
+        fs::write(&manifest, snapshot.to_manifest(&KEY).unwrap()).unwrap();
+
+        (manifest, snapshot)
+    }
 
     #[test]
     fn test_garbage_collector_prunes_old_versions() {
-        let blobs_dir = tempdir().unwrap();
-        let blobs_dir = blobs_dir.path();
-        let mut gc = GarbageCollector::new(blobs_dir.to_path_buf(), 3);
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        let blobs_dir = dest.join("blobs");
+        fs::create_dir_all(&blobs_dir).unwrap();
 
-        let path = tempdir().unwrap();
-        let path = path.path();
-        let hashes = ["h1", "h2", "h3", "h4"];
-        
-        for hash in &hashes {
-            File::create(blobs_dir.join(hash)).unwrap();
-            let _ = gc.register_file(&path.to_path_buf(), &hash, &path.to_path_buf());
+        let mut gc = GarbageCollector::new(blobs_dir.clone(), 3);
+        let mut journal = Journal::begin(dest, "backup").unwrap();
+        let path = PathBuf::from("file.txt");
+
+        // the last version shares its first chunk with the one pruned.
+        let versions = [("h1", ["shared", "b1"]), ("h2", ["b2", "c2"]), ("h3", ["b3", "c3"]), ("h4", ["shared", "b4"])];
+        let mut current = None;
+
+        for (hash, blobs) in versions {
+            let (manifest, snapshot) = write_version(dest, "file.txt", hash, &blobs);
+            gc.register_file(&path, hash, &manifest, &KEY, &mut journal).unwrap();
+            current = Some(snapshot);
         }
 
-        let mut hashes = hashes
-            .map(|v| SnapshotReference::from((v.into(), v.into())));
-            
-        hashes.reverse();
+        gc.remove_unreferenced(&dest.join("snapshot"), &current.unwrap(), &KEY, &mut journal).unwrap();
 
+        // pruned blobs are only deleted once the operation commits.
+        assert!(blobs_dir.join("b1").exists());
+        journal.commit().unwrap();
 
         let current = gc.get_index()
-            .get(&path.to_string_lossy().to_string()).unwrap()
+            .get("file.txt").unwrap()
                 .iter()
                 .map(|f| f.hash_file.clone()).collect::<Vec<String>>();
 
-        assert_eq!(current.len(), 3);
-        let mut expected_hashes = hashes.map(|h| h.hash_file).to_vec();
+        assert_eq!(current, ["h4", "h3", "h2"]);
+        assert!(!blobs_dir.join("b1").exists());
+        assert!(blobs_dir.join("shared").exists());
+        assert!(blobs_dir.join("b2").exists());
 
-        let last = expected_hashes.pop().unwrap();
-        assert_eq!(current, expected_hashes);
-        assert!(!blobs_dir.join(last).exists());
+        // the pruned version is dropped from the manifest that introduced it.
+        let first = Snapshot::from_json_to_snapshot(&dest.join("snapshot/h1.json"), &KEY).unwrap();
+        assert!(first.files.is_empty());
+    }
+
+    #[test]
+    fn test_garbage_collector_keeps_blobs_of_versions_carried_over() {
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        let blobs_dir = dest.join("blobs");
+        fs::create_dir_all(&blobs_dir).unwrap();
+
+        let mut gc = GarbageCollector::new(blobs_dir.clone(), 3);
+        let mut journal = Journal::begin(dest, "backup").unwrap();
+        let path = PathBuf::from("file.txt");
+
+        // the second snapshot carries the first version over, unchanged.
+        let (first, _) = write_version(dest, "file.txt", "h1", &["b1"]);
+        let carried = dest.join("snapshot").join("carried.json");
+        fs::copy(&first, &carried).unwrap();
+        gc.register_file(&path, "h1", &first, &KEY, &mut journal).unwrap();
+        gc.register_file(&path, "h1", &carried, &KEY, &mut journal).unwrap();
+
+        let mut current = None;
+        for (hash, blob) in [("h2", "b2"), ("h3", "b3"), ("h4", "b4")] {
+            let (manifest, snapshot) = write_version(dest, "file.txt", hash, &[blob]);
+            gc.register_file(&path, hash, &manifest, &KEY, &mut journal).unwrap();
+            current = Some(snapshot);
+        }
+
+        gc.remove_unreferenced(&dest.join("snapshot"), &current.unwrap(), &KEY, &mut journal).unwrap();
+        journal.commit().unwrap();
+
+        assert!(Snapshot::from_json_to_snapshot(&first, &KEY).unwrap().files.is_empty());
+        assert_eq!(Snapshot::from_json_to_snapshot(&carried, &KEY).unwrap().files[&path].hash, "h1");
+        assert!(blobs_dir.join("b1").exists());
+    }
+
+    #[test]
+    fn test_manifest_is_encrypted_behind_a_plaintext_header() {
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        fs::create_dir_all(dest.join("blobs")).unwrap();
+
+        let (manifest, snapshot) = write_version(dest, "secret/plans.txt", "h1", &["b1"]);
+        let content = fs::read_to_string(&manifest).unwrap();
+
+        let header: serde_json::Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(header["version"], 1);
+        assert!(header.get("files").is_none() && !content.contains("plans.txt"));
+
+        let read = Snapshot::from_json_to_snapshot(&manifest, &KEY).unwrap();
+        assert_eq!(read.files.keys().collect::<Vec<_>>(), snapshot.files.keys().collect::<Vec<_>>());
+        assert!(Snapshot::from_json_to_snapshot(&manifest, &[8u8; 32]).is_err());
+
+        // manifests written before they were encrypted are still read.
+        fs::write(&manifest, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+        assert_eq!(Snapshot::from_json_to_snapshot(&manifest, &KEY).unwrap().files.len(), 1);
+
+        // a key store marked without the repository key is ignored.
+        let mut store = KeyStore::new(&KEY, "password");
+        store.set_manifest_version(&[8u8; 32], MANIFEST_VERSION);
+        store.save(dest).unwrap();
+        assert_eq!(Snapshot::from_json_to_snapshot(&manifest, &KEY).unwrap().files.len(), 1);
+
+        // until the key store marks every manifest as encrypted.
+        store.set_manifest_version(&KEY, MANIFEST_VERSION);
+        store.save(dest).unwrap();
+        let err = Snapshot::from_json_to_snapshot(&manifest, &KEY).unwrap_err();
+        assert!(err.to_string().contains("is not encrypted"));
+    }
+
+    #[test]
+    fn test_plaintext_manifest_is_rejected_next_to_encrypted_ones() {
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        fs::create_dir_all(dest.join("blobs")).unwrap();
+
+        let (encrypted, _) = write_version(dest, "file.txt", "h1", &["b1"]);
+        let (forged, snapshot) = write_version(dest, "file.txt", "h2", &["b2"]);
+        fs::write(&forged, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        // no key store marks the manifests, yet an encrypted one gives the forged manifest away.
+        assert!(!KeyStore::exists(dest));
+        let err = Snapshot::from_json_to_snapshot(&forged, &KEY).unwrap_err();
+        assert!(err.to_string().contains("is not encrypted"));
+        assert_eq!(Snapshot::from_json_to_snapshot(&encrypted, &KEY).unwrap().files.len(), 1);
+    }
+
+    #[test]
+    fn test_plaintext_manifests_are_encrypted_through_the_journal() {
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        fs::create_dir_all(dest.join("blobs")).unwrap();
+
+        let (manifest, snapshot) = write_version(dest, "file.txt", "h1", &["b1"]);
+        fs::write(&manifest, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let mut journal = Journal::begin(dest, "backup").unwrap();
+        Snapshot::encrypt_plaintext_manifests(&dest.join("snapshot"), &KEY, &mut journal).unwrap();
+
+        // the manifest is only rewritten once the operation commits.
+        assert!(serde_json::from_slice::<Snapshot>(&fs::read(&manifest).unwrap()).is_ok());
+        journal.commit().unwrap();
+
+        let header: serde_json::Value = serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+        assert_eq!(header["version"], MANIFEST_VERSION);
+        assert_eq!(Snapshot::from_json_to_snapshot(&manifest, &KEY).unwrap().files.len(), 1);
+    }
+
+    #[test]
+    fn test_garbage_collector_forgets_versions_of_deleted_snapshot() {
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        fs::create_dir_all(dest.join("blobs")).unwrap();
+
+        let mut gc = GarbageCollector::new(dest.join("blobs"), 3);
+        let mut journal = Journal::begin(dest, "backup").unwrap();
+
+        for (path, hash) in [("a.txt", "h1"), ("a.txt", "h2"), ("b.txt", "h3")] {
+            let (manifest, _) = write_version(dest, path, hash, &[hash]);
+            gc.register_file(&PathBuf::from(path), hash, &manifest, &KEY, &mut journal).unwrap();
+        }
+        journal.commit().unwrap();
+
+        gc.forget_snapshot(&dest.join("snapshot/h2.json"));
+        gc.forget_snapshot(&dest.join("snapshot/h3.json"));
+
+        let versions = gc.get_index()["a.txt"].iter().map(|f| f.hash_file.clone()).collect::<Vec<String>>();
+        assert_eq!(versions, ["h1"]);
+        assert!(!gc.get_index().contains_key("b.txt"));
+    }
+
+    #[test]
+    fn test_unchanged_content_read_under_another_chunk_size_leaves_no_unreferenced_blob() {
+        let src = tempdir().unwrap();
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        fs::create_dir_all(dest.join("blobs")).unwrap();
+        fs::create_dir_all(dest.join("snapshot")).unwrap();
+
+        let file = src.path().join("data.bin");
+        fs::write(&file, (0..200_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect::<Vec<_>>()).unwrap();
+
+        let backup = |chunk_size: u32| {
+            let rules = BackupRules { chunk_size, ..BackupRules::new(vec![], vec![], false, false) };
+            let latest = utils::list_json_snapshots(&dest.join("snapshot")).unwrap().into_iter().next();
+            let engine = utils::generate_compression_engine(None).unwrap().0;
+
+            let mut gc = GarbageCollector::new(dest.join("blobs"), 3);
+            let mut journal = Journal::begin(dest, "backup").unwrap();
+            let snapshot = Snapshot::create(src.path(), &dest.join("blobs"), &KEY, latest.as_ref(), engine, &rules, &mut journal).unwrap();
+            snapshot.save(&dest.join("snapshot"), &KEY, &mut gc, &mut journal).unwrap();
+            journal.commit().unwrap();
+        };
+
+        backup(1024);
+
+        // a chmod makes the file read again, now split into other chunks.
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        backup(8192);
+
+        let referenced: HashSet<String> = utils::list_json_snapshots(&dest.join("snapshot")).unwrap().iter()
+            .flat_map(|manifest| Snapshot::from_json_to_snapshot(manifest, &KEY).unwrap().files.into_values())
+            .flat_map(|entry| entry.blobs().into_iter().map(|chunk| chunk.hash))
+            .collect();
+
+        for blob in fs::read_dir(dest.join("blobs")).unwrap() {
+            let name = blob.unwrap().file_name().to_string_lossy().to_string();
+            assert!(referenced.contains(&name), "blob {name} is not referenced by any manifest");
+        }
     }
 
     #[test]
@@ -105,9 +383,12 @@ mod gc_tests {
         let path = "dir/file.rs";
         let hashes = ["h1", "h1", "h1"];
 
+        let dest = tempdir().unwrap();
+        let mut journal = Journal::begin(dest.path(), "backup").unwrap();
+
         for h in hashes {
             File::create(blobs_dir.join(h)).unwrap();
-            gc.register_file(&PathBuf::from(path), h, &PathBuf::from(path)).unwrap();
+            gc.register_file(&PathBuf::from(path), h, &PathBuf::from(path), &KEY, &mut journal).unwrap();
         }
 
         let current = gc.get_index().get(path).unwrap().iter().map(|f| f.hash_file.clone()).collect::<Vec<String>>();
@@ -121,7 +402,7 @@ mod registry_tests {
 
     use chrono::Utc;
 
-    use crate::{crypto::password, utils::registry::{BackupEntry, BackupRegistry}};
+    use crate::utils::registry::{BackupEntry, BackupRegistry};
 
     fn temp_registry_path() -> String {
         let tmp = std::env::temp_dir().join(format!("snapsafe_test_{}", uuid::Uuid::new_v4()));
@@ -133,7 +414,6 @@ mod registry_tests {
             Utc::now(),
             PathBuf::from("/tmp/source"),
             PathBuf::from("/tmp/backup"),
-            &password::Password::default(),
             "gzip".to_string(),
         )
     }
@@ -260,3 +540,719 @@ mod password_tests {
         assert!(Password::new("password".into(), &policy).is_err())
     }
 }
+
+#[cfg(test)]
+mod diff_tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use chrono::Utc;
+
+    use crate::{file_entry, actions::diff::{ByteSummary, SnapshotDiff}, utils::snapshot::{FileEntry, Snapshot}};
+
+    fn snapshot_with(files: &[(&str, &str)]) -> Snapshot {
+        let files = files.iter()
+            .map(|(path, hash)| {
+                (PathBuf::from(path), file_entry(hash))
+            })
+            .collect::<HashMap<PathBuf, FileEntry>>();
+
+        Snapshot { timestamp: Utc::now(), files, rules: Default::default() }
+    }
+
+    #[test]
+    fn test_diff_reports_every_status() {
+        let old = snapshot_with(&[("a.txt", "h1"), ("b.txt", "h2"), ("c.txt", "h3")]);
+        let new = snapshot_with(&[("a.txt", "h1"), ("b.txt", "h4"), ("d.txt", "h5")]);
+
+        let diff = SnapshotDiff::between(&old, &new);
+
+        assert_eq!(diff.added, vec![PathBuf::from("d.txt")]);
+        assert_eq!(diff.removed, vec![PathBuf::from("c.txt")]);
+        assert_eq!(diff.modified, vec![PathBuf::from("b.txt")]);
+        assert_eq!(diff.unchanged, vec![PathBuf::from("a.txt")]);
+    }
+
+    #[test]
+    fn test_diff_of_identical_snapshots_is_empty() {
+        let old = snapshot_with(&[("a.txt", "h1"), ("logs/b.log", "h2")]);
+        let new = old.clone();
+
+        let diff = SnapshotDiff::between(&old, &new);
+
+        assert!(diff.is_empty());
+        assert_eq!(diff.unchanged.len(), 2);
+    }
+
+    #[test]
+    fn test_diff_compares_digests_rather_than_blob_names() {
+        let mut old = snapshot_with(&[("a.txt", "h1"), ("b.txt", "h2"), ("c.txt", "h3")]);
+        let mut new = snapshot_with(&[("a.txt", "h4"), ("b.txt", "h2"), ("c.txt", "h5")]);
+
+        // the same content stored under another blob name, and other content under the same one.
+        old.files.get_mut(&PathBuf::from("a.txt")).unwrap().digest = Some("d1".into());
+        new.files.get_mut(&PathBuf::from("a.txt")).unwrap().digest = Some("d1".into());
+        old.files.get_mut(&PathBuf::from("b.txt")).unwrap().digest = Some("d2".into());
+        new.files.get_mut(&PathBuf::from("b.txt")).unwrap().digest = Some("d3".into());
+
+        let diff = SnapshotDiff::between(&old, &new);
+
+        assert_eq!(diff.unchanged, vec![PathBuf::from("a.txt")]);
+        // entries without a digest fall back to their blob hash.
+        assert_eq!(diff.modified, vec![PathBuf::from("b.txt"), PathBuf::from("c.txt")]);
+    }
+
+    #[test]
+    fn test_byte_summary_counts_differences_and_first_offset() {
+        let summary = ByteSummary::between(&[0, 1, 2, 3], &[0, 9, 2, 8, 4]);
+
+        assert_eq!(summary.old_size, 4);
+        assert_eq!(summary.new_size, 5);
+        assert_eq!(summary.differing_bytes, 2);
+        assert_eq!(summary.first_difference, Some(1));
+    }
+
+    #[test]
+    fn test_byte_summary_of_truncated_file_points_at_end() {
+        let summary = ByteSummary::between(&[7, 7, 7], &[7, 7]);
+
+        assert_eq!(summary.differing_bytes, 0);
+        assert_eq!(summary.first_difference, Some(2));
+    }
+}
+
+#[cfg(test)]
+mod keyslot_tests {
+    use std::{fs, path::PathBuf};
+
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    use crate::{crypto::{derive_key, keyslot::KeyStore, password::{Password, PasswordPolicy}}, utils::{self, registry::BackupEntry}};
+
+    #[test]
+    fn test_every_slot_unlocks_the_same_master_key() {
+        let master_key: [u8; 32] = rand::random();
+        let mut store = KeyStore::new(&master_key, "first-password");
+        let id = store.add_slot(&master_key, "second-password");
+
+        assert_eq!(id, 2);
+        assert_eq!(store.unlock("first-password").unwrap(), (1, master_key));
+        assert_eq!(store.unlock("second-password").unwrap(), (2, master_key));
+        assert!(store.unlock("third-password").is_err());
+    }
+
+    #[test]
+    fn test_replaced_slot_only_opens_with_new_password() {
+        let master_key: [u8; 32] = rand::random();
+        let mut store = KeyStore::new(&master_key, "old-password");
+
+        assert!(store.replace_slot(1, &master_key, "new-password"));
+        assert!(store.unlock("old-password").is_err());
+        assert_eq!(store.unlock("new-password").unwrap(), (1, master_key));
+    }
+
+    #[test]
+    fn test_last_slot_cannot_be_removed() {
+        let master_key: [u8; 32] = rand::random();
+        let mut store = KeyStore::new(&master_key, "first-password");
+        store.add_slot(&master_key, "second-password");
+
+        assert!(store.remove_slot(1));
+        assert!(!store.remove_slot(2));
+        assert_eq!(store.slots.len(), 1);
+    }
+
+    #[test]
+    fn test_saved_key_store_replaces_the_previous_one() {
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        let master_key: [u8; 32] = rand::random();
+
+        KeyStore::new(&master_key, "first-password").save(dest).unwrap();
+        KeyStore::new(&master_key, "second-password").save(dest).unwrap();
+
+        let store = KeyStore::load(dest).unwrap().unwrap();
+        assert!(store.unlock("first-password").is_err());
+        assert_eq!(store.unlock("second-password").unwrap(), (1, master_key));
+        assert_eq!(fs::read_dir(dest).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_unlock_migrates_backup_made_before_key_slots() {
+        let dest = tempdir().unwrap();
+        let dest = dest.path();
+        let password = "ItisValidP3#";
+
+        let salt = [7u8; 16];
+        fs::write(dest.join("key_salt"), salt).unwrap();
+
+        let mut entry = BackupEntry::new(Utc::now(), PathBuf::from("/tmp/source"), dest.to_path_buf(), "gzip".into());
+        entry.password = Password::new(password.into(), &PasswordPolicy::default()).unwrap();
+
+        // a dry run unlocks the key without saving the key store.
+        let key = utils::unlock_key(dest, password, Some(&entry), false).unwrap();
+        assert!(!KeyStore::exists(dest));
+
+        assert_eq!(utils::unlock_key(dest, password, Some(&entry), true).unwrap(), key);
+        assert_eq!(key, derive_key(password, &salt));
+        assert!(KeyStore::exists(dest));
+        assert_eq!(utils::unlock_key(dest, password, None, true).unwrap(), key);
+    }
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use std::fs;
+
+    use sha2::{Digest, Sha256};
+    use tempfile::tempdir;
+
+    use crate::{file_entry, actions::verify::{check_entry, BlobStatus}, compress::build_engine, crypto::encrypt_file_bytes, utils::snapshot::FileEntry};
+
+    fn store_blob(blobs_dir: &std::path::Path, content: &[u8], key: &[u8]) -> FileEntry {
+        let hash = format!("{:x}", Sha256::digest(content));
+        let (ciphertext, nonce) = encrypt_file_bytes(content, key);
+        fs::write(blobs_dir.join(&hash), ciphertext).unwrap();
+
+        FileEntry { nonce, size: content.len() as u64, ..file_entry(&hash) }
+    }
+
+    #[test]
+    fn test_check_entry_detects_each_failure() {
+        let blobs_dir = tempdir().unwrap();
+        let blobs_dir = blobs_dir.path();
+        let key = [1u8; 32];
+
+        let engine = build_engine("none".into()).unwrap();
+        let engine = engine.as_ref();
+
+        let entry = store_blob(blobs_dir, b"some content", &key);
+        assert_eq!(check_entry(&entry, blobs_dir, &key, engine), BlobStatus::Ok);
+        assert_eq!(check_entry(&entry, blobs_dir, &[2u8; 32], engine), BlobStatus::Undecryptable);
+
+        let mut renamed = entry.clone();
+        renamed.hash = "not-the-hash".into();
+        fs::copy(blobs_dir.join(&entry.hash), blobs_dir.join(&renamed.hash)).unwrap();
+        assert_eq!(check_entry(&renamed, blobs_dir, &key, engine), BlobStatus::HashMismatch);
+
+        // the blob matches its name, but not the digest of the content recorded for the file.
+        let mut digested = entry.clone();
+        digested.digest = Some(format!("{:x}", Sha256::digest(b"some content")));
+        assert_eq!(check_entry(&digested, blobs_dir, &key, engine), BlobStatus::Ok);
+        digested.digest = Some(format!("{:x}", Sha256::digest(b"other content")));
+        assert_eq!(check_entry(&digested, blobs_dir, &key, engine), BlobStatus::HashMismatch);
+
+        fs::remove_file(blobs_dir.join(&entry.hash)).unwrap();
+        assert_eq!(check_entry(&entry, blobs_dir, &key, engine), BlobStatus::Missing);
+    }
+}
+
+#[cfg(test)]
+mod ls_tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use chrono::Utc;
+
+    use crate::{file_entry, actions::ls::{render_tree, LsOptions}, utils::snapshot::{EntryKind, FileEntry, Snapshot}};
+
+    fn sample_snapshot() -> Snapshot {
+        let files = ["README.md", "src/main.rs", "src/utils/mod.rs", "logs/app.log"].iter()
+            .map(|path| {
+                (PathBuf::from(path), FileEntry { size: 10, ..file_entry("abc") })
+            })
+            .collect::<HashMap<PathBuf, FileEntry>>();
+
+        Snapshot { timestamp: Utc::now(), files, rules: Default::default() }
+    }
+
+    #[test]
+    fn test_ls_lists_direct_children_only() {
+        let lines = render_tree(&sample_snapshot(), &LsOptions::default()).unwrap();
+
+        assert_eq!(lines, vec!["README.md", "logs/", "src/"]);
+    }
+
+    #[test]
+    fn test_ls_recursive_under_prefix_prints_tree() {
+        let options = LsOptions { prefix: Some(PathBuf::from("./src/")), recursive: true, ..Default::default() };
+        let lines = render_tree(&sample_snapshot(), &options).unwrap();
+
+        assert_eq!(lines, vec!["main.rs", "utils/", "  mod.rs"]);
+    }
+
+    #[test]
+    fn test_ls_glob_keeps_matching_files_and_their_directories() {
+        let options = LsOptions { recursive: true, glob: Some("*.rs".into()), ..Default::default() };
+        let lines = render_tree(&sample_snapshot(), &options).unwrap();
+
+        assert_eq!(lines, vec!["src/", "  main.rs", "  utils/", "    mod.rs"]);
+    }
+
+    #[test]
+    fn test_ls_shows_empty_directories_and_symlink_targets() {
+        let mut snapshot = sample_snapshot();
+        let entry = |kind| FileEntry { isupdated: false, kind, ..file_entry("") };
+        snapshot.files.insert(PathBuf::from("src"), entry(EntryKind::Dir));
+        snapshot.files.insert(PathBuf::from("empty"), entry(EntryKind::Dir));
+        snapshot.files.insert(PathBuf::from("src/link"), entry(EntryKind::Symlink { target: PathBuf::from("main.rs") }));
+
+        let options = LsOptions { prefix: Some(PathBuf::from("src")), ..Default::default() };
+        assert_eq!(render_tree(&snapshot, &options).unwrap(), vec!["link", "main.rs", "utils/"]);
+
+        let lines = render_tree(&snapshot, &LsOptions { recursive: true, long: true, ..Default::default() }).unwrap();
+        let names: Vec<&str> = lines.iter().map(|line| line[49..].trim_end()).collect();
+        assert_eq!(names, vec!["README.md", "empty/", "logs/", "  app.log", "src/", "  link -> main.rs", "  main.rs", "  utils/", "    mod.rs"]);
+    }
+}
+
+#[cfg(test)]
+mod restore_filter_tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::actions::restore::RestoreFilter;
+
+    #[test]
+    fn test_empty_filter_selects_everything() {
+        let filter = RestoreFilter::new(Vec::new(), &[], &[]).unwrap();
+
+        assert!(filter.is_empty());
+        assert!(filter.matches(Path::new("any/file.txt")));
+    }
+
+    #[test]
+    fn test_filter_combines_paths_include_and_exclude() {
+        let include = ["*.rs".to_string(), "*.toml".to_string()];
+        let exclude = ["**/generated/**".to_string()];
+        let filter = RestoreFilter::new(vec![PathBuf::from("./src")], &include, &exclude).unwrap();
+
+        assert!(filter.matches(Path::new("src/main.rs")));
+        assert!(!filter.matches(Path::new("src/notes.md")));
+        assert!(!filter.matches(Path::new("src/generated/api.rs")));
+        assert!(!filter.matches(Path::new("srcs/main.rs")));
+        assert!(!filter.matches(Path::new("Cargo.toml")));
+    }
+
+    #[test]
+    fn test_filter_rejects_invalid_glob() {
+        assert!(RestoreFilter::new(Vec::new(), &["a[".to_string()], &[]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod selector_tests {
+    use std::fs;
+
+    use chrono::{NaiveDate, Utc};
+    use tempfile::tempdir;
+
+    use crate::utils::selector::SnapshotSelector;
+
+    #[test]
+    fn test_selector_parses_latest_and_ids() {
+        assert_eq!("latest".parse::<SnapshotSelector>().unwrap(), SnapshotSelector::Latest(0));
+        assert_eq!("latest~3".parse::<SnapshotSelector>().unwrap(), SnapshotSelector::Latest(3));
+        assert_eq!(
+            "2024-05-01T10-00-00-000000".parse::<SnapshotSelector>().unwrap(),
+            SnapshotSelector::Id("2024-05-01T10-00-00-000000".into())
+        );
+        assert!("latest~x".parse::<SnapshotSelector>().is_err());
+        assert!("".parse::<SnapshotSelector>().is_err());
+        assert!("../keys".parse::<SnapshotSelector>().is_err());
+    }
+
+    #[test]
+    fn test_selector_at_plain_date_means_end_of_day() {
+        let end_of_day = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_micro_opt(23, 59, 59, 999_999).unwrap().and_utc();
+
+        assert_eq!(SnapshotSelector::at("2024-05-01").unwrap(), SnapshotSelector::At(end_of_day));
+        assert!(SnapshotSelector::at("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_selector_resolves_by_snapshot_id_not_modification_time() {
+        let dir = tempdir().unwrap();
+        let ids = ["2024-05-03T08-00-00-000000", "2024-05-01T08-00-00-000000", "2024-05-02T08-00-00"];
+
+        // written newest first, so modification times are in the opposite order of the ids.
+        for id in ids {
+            fs::write(dir.path().join(format!("{id}.json")), "{}").unwrap();
+        }
+
+        let resolve = |selector: SnapshotSelector| {
+            selector.resolve(dir.path()).unwrap().map(|path| path.file_stem().unwrap().to_string_lossy().to_string())
+        };
+
+        assert_eq!(resolve(SnapshotSelector::Latest(0)).unwrap(), ids[0]);
+        assert_eq!(resolve(SnapshotSelector::Latest(2)).unwrap(), ids[1]);
+        assert_eq!(resolve(SnapshotSelector::Latest(3)), None);
+        assert_eq!(resolve(SnapshotSelector::at("2024-05-02 12:00:00").unwrap()).unwrap(), ids[2]);
+        assert_eq!(resolve(SnapshotSelector::at("2024-04-30").unwrap()), None);
+        assert_eq!(resolve(SnapshotSelector::At(Utc::now())).unwrap(), ids[0]);
+        assert_eq!(resolve(SnapshotSelector::Id(ids[1].into())).unwrap(), ids[1]);
+
+        // ids are only looked up when they name a manifest.
+        fs::write(dir.path().join("keys.json"), "{}").unwrap();
+        assert_eq!(resolve(SnapshotSelector::Id("keys".into())), None);
+    }
+}
+
+#[cfg(test)]
+mod restore_conflict_tests {
+    use std::{fs, path::PathBuf, time::{Duration, SystemTime}};
+
+    use tempfile::tempdir;
+
+    use crate::{file_entry, actions::restore::{resolve_conflict, ConflictPolicy, RestoreOutcome}, utils::snapshot::FileEntry};
+
+    fn entry_modified_at(modified: SystemTime) -> FileEntry {
+        FileEntry { modified, size: 10, ..file_entry("abc") }
+    }
+
+    #[test]
+    fn test_missing_target_is_written_whatever_the_policy() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().join("new.txt");
+
+        let (write_to, outcome) = resolve_conflict(&target, &entry_modified_at(SystemTime::now()), ConflictPolicy::Fail, "ts").unwrap();
+
+        assert_eq!(write_to, Some(target));
+        assert_eq!(outcome, RestoreOutcome::Written);
+    }
+
+    #[test]
+    fn test_existing_target_follows_policy() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().join("notes.txt");
+        fs::write(&target, "local edits").unwrap();
+
+        let older = entry_modified_at(SystemTime::now() - Duration::from_secs(3600));
+        let newer = entry_modified_at(SystemTime::now() + Duration::from_secs(3600));
+
+        let resolve = |entry: &FileEntry, policy| resolve_conflict(&target, entry, policy, "20240501T100000");
+
+        assert_eq!(resolve(&older, ConflictPolicy::Overwrite).unwrap(), (Some(target.clone()), RestoreOutcome::Overwritten));
+        assert_eq!(resolve(&older, ConflictPolicy::Skip).unwrap(), (None, RestoreOutcome::Skipped));
+        assert_eq!(resolve(&older, ConflictPolicy::KeepNewer).unwrap(), (None, RestoreOutcome::KeptNewer));
+        assert_eq!(resolve(&newer, ConflictPolicy::KeepNewer).unwrap(), (Some(target.clone()), RestoreOutcome::Overwritten));
+        assert!(resolve(&older, ConflictPolicy::Fail).is_err());
+
+        let renamed = dir.path().join(PathBuf::from("notes.txt.restored-20240501T100000"));
+        assert_eq!(resolve(&older, ConflictPolicy::Rename).unwrap(), (Some(renamed.clone()), RestoreOutcome::Renamed(renamed)));
+    }
+
+    #[test]
+    fn test_conflict_policy_parses_cli_names() {
+        assert_eq!("keep-newer".parse::<ConflictPolicy>().unwrap(), ConflictPolicy::KeepNewer);
+        assert!("newest".parse::<ConflictPolicy>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use crate::utils::journal::Journal;
+
+    #[test]
+    fn test_journal_commit_moves_writes_and_applies_removals() {
+        let dest = tempdir().unwrap();
+        let (written, removed) = (dest.path().join("new.json"), dest.path().join("old.json"));
+        fs::write(&removed, "old").unwrap();
+
+        let mut journal = Journal::begin(dest.path(), "backup").unwrap();
+        journal.write(&written, b"new").unwrap();
+        journal.remove(&removed);
+
+        assert!(!written.exists());
+        assert!(removed.exists());
+
+        journal.commit().unwrap();
+
+        assert_eq!(fs::read_to_string(&written).unwrap(), "new");
+        assert!(!removed.exists());
+        assert!(!Journal::pending_path(&written).exists());
+        assert!(!Journal::path(dest.path()).exists());
+    }
+
+    #[test]
+    fn test_recover_rolls_back_uncommitted_operation() {
+        let dest = tempdir().unwrap();
+        fs::create_dir_all(dest.path().join("blobs")).unwrap();
+        let (blob, removed) = (dest.path().join("blobs").join("abc"), dest.path().join("old.json"));
+        fs::write(&removed, "old").unwrap();
+
+        let mut journal = Journal::begin(dest.path(), "backup").unwrap();
+        journal.write(&blob, b"blob").unwrap();
+        journal.remove(&removed);
+        // the process dies here, before committing.
+        drop(journal);
+
+        let message = Journal::recover(dest.path()).unwrap().unwrap();
+
+        assert!(message.starts_with("Rolled back the interrupted backup"));
+        assert!(!blob.exists());
+        assert!(!Journal::pending_path(&blob).exists());
+        assert!(removed.exists());
+        assert!(Journal::recover(dest.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_begin_refuses_to_run_while_another_operation_holds_the_lock() {
+        let dest = tempdir().unwrap();
+        let blob = dest.path().join("abc");
+
+        let mut running = Journal::begin(dest.path(), "backup").unwrap();
+        running.write(&blob, b"blob").unwrap();
+
+        // the running operation is neither rolled back nor taken over.
+        let err = Journal::begin(dest.path(), "delete").unwrap_err();
+        assert!(err.to_string().contains("Another operation is in progress"));
+        assert!(Journal::recover(dest.path()).is_err());
+        assert!(Journal::pending_path(&blob).exists());
+
+        running.commit().unwrap();
+        assert_eq!(fs::read_to_string(&blob).unwrap(), "blob");
+        assert!(Journal::begin(dest.path(), "delete").is_ok());
+    }
+}
+
+#[cfg(test)]
+mod delete_plan_tests {
+    use std::{collections::HashMap, path::{Path, PathBuf}};
+
+    use chrono::Utc;
+
+    use crate::{file_entry, actions::delete::DeletePlan, utils::snapshot::{FileEntry, Snapshot}};
+
+    fn snapshot(files: &[(&str, &str)]) -> Snapshot {
+        let files = files.iter()
+            .map(|(path, hash)| {
+                (PathBuf::from(path), FileEntry { size: 1, ..file_entry(hash) })
+            })
+            .collect::<HashMap<PathBuf, FileEntry>>();
+
+        Snapshot { timestamp: Utc::now(), files, rules: Default::default() }
+    }
+
+    #[test]
+    fn test_delete_plan_keeps_blobs_other_snapshots_reference() {
+        let deleted = snapshot(&[("a.txt", "h1"), ("b.txt", "h2"), ("copy_of_b.txt", "h2")]);
+        let newer = snapshot(&[("a.txt", "h1"), ("b.txt", "h3")]);
+
+        let plan = DeletePlan::new(Path::new("old.json"), &deleted, &[newer]);
+
+        assert_eq!(plan.kept_blobs, vec![("h1".to_string(), PathBuf::from("a.txt"))]);
+        assert_eq!(plan.removed_blobs, vec![("h2".to_string(), PathBuf::from("b.txt"))]);
+    }
+}
+
+#[cfg(test)]
+mod backup_rules_tests {
+    use std::{fs, path::PathBuf};
+
+    use tempfile::tempdir;
+
+    use crate::utils::rules::{BackupRules, RuleMatcher};
+
+    #[test]
+    fn test_rules_apply_ignore_files_from_the_deepest_directory_first() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join(".snapsafeignore"), "*.md\n# comment\n").unwrap();
+        fs::write(root.join("docs").join(".snapsafeignore"), "!README.md\n").unwrap();
+        fs::write(root.join(".gitignore"), "*.txt\n").unwrap();
+
+        let mut matcher = RuleMatcher::new(root, &BackupRules::default()).unwrap();
+
+        assert!(matcher.is_excluded(&root.join("notes.md"), false).unwrap());
+        assert!(!matcher.is_excluded(&root.join("docs").join("README.md"), false).unwrap());
+        // .gitignore files are only read when asked for.
+        assert!(!matcher.is_excluded(&root.join("notes.txt"), false).unwrap());
+
+        let rules = matcher.into_rules();
+        assert_eq!(rules.ignore_files[&PathBuf::from(".snapsafeignore")], vec!["*.md".to_string()]);
+        assert_eq!(rules.ignore_files.len(), 2);
+    }
+
+    #[test]
+    fn test_rules_include_wins_over_exclude_and_gitignore() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+
+        let rules = BackupRules::new(vec!["target/".into()], vec!["keep.log".into()], true, true);
+        let mut matcher = RuleMatcher::new(root, &rules).unwrap();
+
+        assert!(matcher.is_excluded(&root.join("target"), true).unwrap());
+        assert!(!matcher.is_excluded(&root.join("target"), false).unwrap());
+        assert!(matcher.is_excluded(&root.join("debug.log"), false).unwrap());
+        assert!(!matcher.is_excluded(&root.join("keep.log"), false).unwrap());
+    }
+
+    #[test]
+    fn test_rules_skip_tagged_cache_directories() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("cache")).unwrap();
+        fs::create_dir_all(root.join("fake")).unwrap();
+        fs::write(root.join("cache").join("CACHEDIR.TAG"), "Signature: 8a477f597d28d172789f06886806bc55\n").unwrap();
+        fs::write(root.join("fake").join("CACHEDIR.TAG"), "not a cache\n").unwrap();
+
+        let mut matcher = RuleMatcher::new(root, &BackupRules::new(vec![], vec![], false, true)).unwrap();
+
+        assert!(matcher.is_excluded(&root.join("cache"), true).unwrap());
+        assert!(!matcher.is_excluded(&root.join("fake"), true).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod metadata_tests {
+    use std::{fs, os::unix::fs::PermissionsExt, time::{Duration, SystemTime}};
+
+    use tempfile::tempdir;
+
+    use crate::{actions::restore::{IdMapping, OwnerOptions}, utils::metadata::FileMetadata};
+
+    #[test]
+    fn test_metadata_is_applied_to_another_file() {
+        let dir = tempdir().unwrap();
+        let original = dir.path().join("script.sh");
+        let restored = dir.path().join("restored.sh");
+        fs::write(&original, "#!/bin/sh\n").unwrap();
+        fs::write(&restored, "#!/bin/sh\n").unwrap();
+
+        fs::set_permissions(&original, fs::Permissions::from_mode(0o750)).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::File::open(&original).unwrap().set_modified(modified).unwrap();
+
+        let metadata = FileMetadata::read(&original, &fs::metadata(&original).unwrap()).unwrap();
+        let issues = metadata.apply(&restored, None).unwrap();
+
+        let restored_metadata = fs::metadata(&restored).unwrap();
+        assert!(!issues.owner);
+        assert_eq!(restored_metadata.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(restored_metadata.modified().unwrap(), modified);
+    }
+
+    #[test]
+    fn test_owner_options_remap_or_skip_ids() {
+        let metadata = FileMetadata {
+            mode: 0o644, uid: 1000, gid: 100, accessed: SystemTime::now(), modified: SystemTime::now(), xattrs: Default::default(),
+        };
+
+        let mapping: IdMapping = "1000:2000".parse().unwrap();
+        let remapped = OwnerOptions::new(false, &[mapping], &[]);
+
+        assert_eq!(remapped.owner_of(&metadata), Some((2000, 100)));
+        assert_eq!(OwnerOptions::new(true, &[mapping], &[]).owner_of(&metadata), None);
+        assert!("1000".parse::<IdMapping>().is_err());
+        assert!("a:1".parse::<IdMapping>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod chunker_tests {
+    use std::io::{self, Read};
+
+    use crate::utils::chunker::{stream, validate_chunk_size, DEFAULT_CHUNK_SIZE};
+
+    /// `len` bytes of deterministic pseudo random content, which has chunk boundaries unlike repeated bytes.
+    fn content(len: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    /// The chunks `stream` splits `data` into.
+    fn split(data: impl Read, avg_size: u32) -> Vec<Vec<u8>> {
+        stream(data, avg_size).collect::<Result<_, _>>().unwrap()
+    }
+
+    /// Reader handing out at most 1000 bytes per read.
+    struct ShortReads<'a>(&'a [u8]);
+
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1000);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_stream_keeps_content_and_bounds() {
+        let data = content(200_000);
+        let chunks = split(data.as_slice(), 4096);
+
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks.len() > 10);
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| (1024..=16384).contains(&chunk.len())));
+        assert!(split(io::empty(), 4096).is_empty());
+    }
+
+    #[test]
+    fn test_insertion_only_changes_nearby_chunks() {
+        let data = content(200_000);
+        let mut edited = data.clone();
+        edited.splice(100_000..100_000, b"inserted".iter().copied());
+
+        let before = split(data.as_slice(), 4096);
+        let after = split(edited.as_slice(), 4096);
+        let shared = after.iter().filter(|chunk| before.contains(chunk)).count();
+
+        assert_eq!(split(data.as_slice(), 4096), before);
+        assert!(shared >= after.len() - 3, "only {shared} of {} chunks are shared", after.len());
+    }
+
+    #[test]
+    fn test_stream_splits_the_same_whatever_the_read_sizes() {
+        let data = content(200_000);
+
+        assert_eq!(split(ShortReads(&data), 4096), split(data.as_slice(), 4096));
+    }
+
+    #[test]
+    fn test_validate_chunk_size() {
+        assert!(validate_chunk_size(DEFAULT_CHUNK_SIZE).is_ok());
+        assert!(validate_chunk_size(4096).is_ok());
+        assert!(validate_chunk_size(0).is_err());
+        assert!(validate_chunk_size(u32::MAX).is_err());
+    }
+}
+
+#[cfg(test)]
+mod parallel_tests {
+    use crate::utils::{run_parallel, worker_count};
+
+    #[test]
+    fn test_run_parallel_keeps_item_order() {
+        let items: Vec<u64> = (0..100).collect();
+
+        let results = run_parallel(&items, 8, |item| {
+            // later items finish first.
+            std::thread::sleep(std::time::Duration::from_micros(100 - item));
+            Ok::<_, String>(item * 2)
+        }).unwrap();
+
+        assert_eq!(results, items.iter().map(|item| item * 2).collect::<Vec<_>>());
+        assert!(run_parallel(&[] as &[u64], 4, |item| Ok::<_, String>(*item)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_parallel_returns_first_error() {
+        let items: Vec<u64> = (0..50).collect();
+
+        let result = run_parallel(&items, 4, |item| if *item >= 10 { Err(format!("item {item}")) } else { Ok(*item) });
+
+        assert_eq!(result, Err("item 10".to_string()));
+        assert_eq!(worker_count(3), 3);
+        assert!(worker_count(0) >= 1);
+    }
+}
@@ -0,0 +1,29 @@
+use std::io::{self, Read};
+
+use fastcdc::v2020::{StreamCDC, AVERAGE_MAX, AVERAGE_MIN};
+
+use crate::utils::error::SnapError;
+
+/// Average chunk size used when the config does not set one, 1 MiB.
+pub const DEFAULT_CHUNK_SIZE: u32 = 1_048_576;
+
+/// Check that `avg_size` is an average chunk size FastCDC supports.
+pub fn validate_chunk_size(avg_size: u32) -> Result<(), SnapError> {
+    if !(AVERAGE_MIN..=AVERAGE_MAX).contains(&avg_size) {
+        let message = format!("Invalid chunk size {avg_size}, expected between {AVERAGE_MIN} and {AVERAGE_MAX} bytes.");
+        return Err(SnapError::Config(message));
+    }
+
+    Ok(())
+}
+
+/// Split what `reader` yields into content-defined chunks of `avg_size` bytes on average, with FastCDC,
+/// holding at most one chunk in memory at a time.
+///
+/// Boundaries only depend on the bytes around them, so an insertion or deletion only changes the chunks it touches
+/// and identical content is always split the same way. Chunks are between a quarter of and four times `avg_size`,
+/// except the last one which may be smaller. Empty content has no chunk.
+pub fn stream<R: Read>(reader: R, avg_size: u32) -> impl Iterator<Item = io::Result<Vec<u8>>> {
+    StreamCDC::new(reader, avg_size / 4, avg_size, avg_size * 4)
+        .map(|chunk| chunk.map(|chunk| chunk.data).map_err(io::Error::from))
+}
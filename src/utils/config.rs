@@ -6,7 +6,7 @@ use core::convert::From;
 
 use serde::{Deserialize, Serialize};
 
-use crate::utils::{config_utils, error::SnapError};
+use crate::utils::{chunker, config_utils, error::SnapError};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -22,7 +22,14 @@ pub struct GeneralConfig {
     pub registry_dir: String,
     pub compression: String,
     pub encryption: bool,
-    pub gc_limit: usize
+    pub gc_limit: usize,
+    /// average size in bytes of the chunks files are split into, see `chunker::stream`.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u32,
+}
+
+fn default_chunk_size() -> u32 {
+    chunker::DEFAULT_CHUNK_SIZE
 }
 
 // pub struct SecurityConfig {
@@ -61,6 +68,7 @@ impl From<(String, String, usize)> for GeneralConfig {
             compression: comp,
             encryption: true,
             gc_limit: limit,
+            chunk_size: chunker::DEFAULT_CHUNK_SIZE,
         }
     }
 }
@@ -111,11 +119,20 @@ impl GeneralConfig {
             }
         };
 
+        let chunk_size = match config_utils::get_chunk_size() {
+            Some(size) => size,
+            None => {
+                println!("Average chunk size is set to {} bytes. You can change it with snapsafe config --global or snapsafe config --local", chunker::DEFAULT_CHUNK_SIZE);
+                chunker::DEFAULT_CHUNK_SIZE
+            }
+        };
+
         Some(Self {
             registry_dir: registry,
             compression,
             encryption: true,
-            gc_limit
+            gc_limit,
+            chunk_size,
         })
     }
 }
\ No newline at end of file
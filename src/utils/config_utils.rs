@@ -1,7 +1,7 @@
 use core::convert::From;
 use std::{fs::{self, File}, io::{self, stdin, stdout, Write}, path::PathBuf};
 
-use crate::utils::{self, config::{Config, GeneralConfig}, error::SnapError};
+use crate::utils::{self, chunker, config::{Config, GeneralConfig}, error::SnapError};
 
 pub fn get_compression_type() -> Option<String> {
     print!("Provide the compression algorithm you prefer [gzip, zlib, brotli, zstd, lzma]: ");
@@ -50,6 +50,39 @@ pub fn get_gc_limit() -> Option<usize> {
     Some(limit)
 }
 
+pub fn get_chunk_size() -> Option<u32> {
+    print!("Indicate the average size in bytes of the chunks files are split into. Default is {}: ", chunker::DEFAULT_CHUNK_SIZE);
+    stdout().flush().unwrap();
+
+    let mut input = String::new();
+
+    let size = match stdin().read_line(&mut input) {
+        Ok(_) => {
+            let response = input.trim();
+            if response.is_empty() {
+                return None
+            }
+            // the default is used instead of a rejected size, so tell why.
+            let size = response.parse::<u32>()
+                .map_err(|_| SnapError::Config(format!("Invalid chunk size {response}, expected a number of bytes.")))
+                .and_then(|val| chunker::validate_chunk_size(val).map(|_| val));
+
+            match size {
+                Ok(val) => val,
+                Err(err) => {
+                    println!("{err}");
+                    return None
+                }
+            }
+        }
+        Err(_) => {
+            return None
+        }
+    };
+
+    Some(size)
+}
+
 pub fn get_registry_dir() -> Option<String> {
     print!("Provide your registry directory path or press D for default: ");
     stdout().flush().unwrap();
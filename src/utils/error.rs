@@ -10,10 +10,13 @@ pub enum SnapError {
     Backup(String),
     Restore(String),
     Delete(String),
+    Diff(String),
+    KeyStore(String),
+    Verify(String),
     Password(PasswordError),
     IOError(io::Error),
     DirError(walkdir::Error),
-    EncryptError(String, Box<dyn error::Error>),
+    EncryptError(String, Box<dyn error::Error + Send + Sync>),
     InvalidCompressor(String),
 }
 
@@ -25,6 +28,9 @@ impl fmt::Display for SnapError {
             SnapError::Backup(msg) => write!(f, "Backup Error: {msg}"),
             SnapError::Restore(msg) => write!(f, "Restore Error: {msg}"),
             SnapError::Delete(msg) => write!(f, "Delete Error: {msg}"),
+            SnapError::Diff(msg) => write!(f, "Diff Error: {msg}"),
+            SnapError::KeyStore(msg) => write!(f, "Key Store Error: {msg}"),
+            SnapError::Verify(msg) => write!(f, "Verify Error: {msg}"),
             SnapError::Password(err) => write!(f, "Password Error: {err:?}"),
             SnapError::IOError(err) => write!(f, "IO Error: {err}"),
             SnapError::DirError(err) => write!(f, "Directory Traversal Error: {err:?}"),
@@ -34,8 +40,8 @@ impl fmt::Display for SnapError {
     }
 }
 
-impl From<(String, Box<dyn error::Error>)> for SnapError {
-    fn from(ctx: (String, Box<dyn error::Error>)) -> Self {
+impl From<(String, Box<dyn error::Error + Send + Sync>)> for SnapError {
+    fn from(ctx: (String, Box<dyn error::Error + Send + Sync>)) -> Self {
         let msg = ctx.0;
         let err = ctx.1;
         Self::EncryptError(msg, err)
@@ -1,14 +1,17 @@
-use std::{collections::HashMap, fs, io::{self, Write}, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, fs, io::{self, Write}, path::{Path, PathBuf}};
 
 use serde::{Deserialize, Serialize};
 
-use crate::utils::{error::SnapError, snapshot::Snapshot};
+use crate::utils::{self, error::SnapError, journal::Journal, snapshot::{FileEntry, Snapshot}};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GarbageCollector {
     version_index: HashMap<String, Vec<SnapshotReference>>,
     max_versions: usize,
-    blobs_dir: PathBuf
+    blobs_dir: PathBuf,
+    /// blobs of the versions pruned since the last `remove_unreferenced`.
+    #[serde(skip)]
+    pruned: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -94,12 +97,20 @@ impl GarbageCollector {
         Self {
             version_index: HashMap::new(),
             max_versions,
-            blobs_dir
+            blobs_dir,
+            pruned: Vec::new(),
         }
     }
 
-    pub fn register_file(&mut self, path: &PathBuf, hash: &str, snap_path: &PathBuf) -> io::Result<()> {
-        let hashes = self.version_index.entry(path.to_string_lossy().to_string()).or_default();
+    /// Record `hash` as the latest version of `path`, introduced by the manifest at `snap_path`.
+    /// Manifests are decrypted and encrypted again with `key`.
+    ///
+    /// Versions beyond `max_versions` are pruned through `journal`: they are removed from the manifest that
+    /// introduced them when the operation owning the journal commits. Their blobs may hold chunks other files
+    /// still use, so they are only removed by `remove_unreferenced`.
+    pub fn register_file(&mut self, path: &PathBuf, hash: &str, snap_path: &PathBuf, key: &[u8], journal: &mut Journal) -> io::Result<()> {
+        let index_key = path.to_string_lossy().to_string();
+        let hashes = self.version_index.entry(index_key).or_default();
 
         let first_hash = if let Some(s_reference) = hashes.first() {
             s_reference.hash_file.clone()
@@ -116,28 +127,82 @@ impl GarbageCollector {
         while hashes.len() > self.max_versions {
             if let Some(old_ref) = hashes.pop() {
                 let hash_file = old_ref.hash_file;
-                let snap_file = old_ref.snapshot_path;
-                let blob_path = self.blobs_dir.join(&hash_file);
+                let snap_path = PathBuf::from(old_ref.snapshot_path);
+
+                // the manifest may be gone already, the version then is a whole-file blob named after its hash.
+                let mut snapshot = if journal.is_removed(&snap_path) {
+                    None
+                } else {
+                    Snapshot::from_json_to_snapshot(&journal.staged(&snap_path), key).ok()
+                };
+
+                let entry = snapshot.as_mut()
+                    .and_then(|snap| snap.files.remove(path))
+                    .filter(|entry| entry.hash == hash_file);
+
+                match &entry {
+                    Some(entry) => self.pruned.extend(entry.blobs().into_iter().map(|chunk| chunk.hash)),
+                    None => self.pruned.push(hash_file),
+                }
 
-                if blob_path.exists() {
-                    fs::remove_file(blob_path)?;
+                if let (Some(snapshot), Some(_)) = (snapshot, entry) {
+                    journal.write(&snap_path, &snapshot.to_manifest(key)?)?;
                 }
+            }
+        }
 
-                let snap_path = PathBuf::from(snap_file);
+        Ok(())
+    }
 
-                let mut snapshot = Snapshot::from_json_to_snapshot(&snap_path)?;
-                let mut files = snapshot.files;
-                files.remove(&PathBuf::from(hash_file));
+    /// Remove through `journal` the blobs of the versions pruned so far that no entry of `current`
+    /// or of the manifests in `snapshot_dir`, decrypted with `key`, still uses.
+    ///
+    /// Later snapshots carry unchanged files over, so a pruned version may still be listed by them: its blobs then stay.
+    pub fn remove_unreferenced(&mut self, snapshot_dir: &Path, current: &Snapshot, key: &[u8], journal: &mut Journal) -> io::Result<()> {
+        if self.pruned.is_empty() {
+            return Ok(());
+        }
 
-                snapshot.files = files;
+        // a file may come back to a pruned version, every blob of the new snapshot stays.
+        let mut in_use: HashSet<String> = current.files.values().flat_map(FileEntry::blobs).map(|chunk| chunk.hash).collect();
 
-                snapshot.save_snapshot(&snap_path)?;
+        for manifest in utils::list_json_snapshots(snapshot_dir)? {
+            if journal.is_removed(&manifest) {
+                continue;
             }
+
+            let snapshot = Snapshot::from_json_to_snapshot(&journal.staged(&manifest), key)?;
+            in_use.extend(snapshot.files.values().flat_map(FileEntry::blobs).map(|chunk| chunk.hash));
         }
 
+        let unused: HashSet<String> = self.pruned.iter()
+            .filter(|blob| !in_use.contains(*blob))
+            .cloned()
+            .collect();
+
+        for blob in unused {
+            let blob_path = self.blobs_dir.join(blob);
+
+            if blob_path.exists() {
+                journal.remove(&blob_path);
+            }
+        }
+
+        self.pruned.clear();
+
         Ok(())
     }
 
+    /// Drop the versions introduced by the manifest at `snap_path`, once that manifest was deleted.
+    pub fn forget_snapshot(&mut self, snap_path: &Path) {
+        let snap_path = snap_path.to_string_lossy();
+
+        for references in self.version_index.values_mut() {
+            references.retain(|reference| reference.snapshot_path != snap_path);
+        }
+        self.version_index.retain(|_, references| !references.is_empty());
+    }
+
     pub fn get_index(&self) -> &HashMap<String, Vec<SnapshotReference>> {
         &self.version_index
     }
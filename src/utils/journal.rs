@@ -0,0 +1,325 @@
+use std::{collections::{HashMap, HashSet}, ffi::OsStr, fs, io::{self, Write}, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use nix::{errno::Errno, fcntl::{Flock, FlockArg}};
+
+use crate::utils::error::SnapError;
+
+/// Extension added to files written by an operation that has not been committed yet.
+const PENDING_EXTENSION: &str = "pending";
+
+/// struct `Journal` is the write-ahead journal of one operation changing a backup destination,
+/// saved as `journal.json` in the destination while the operation runs.
+///
+/// Until the operation commits, every file it writes lives next to its final path with a `.pending`
+/// extension and every file it deletes is only recorded. Committing marks the journal as committed
+/// and then moves the pending files into place and deletes the recorded files. A journal left behind
+/// by a crash is rolled back when it was not committed, and finished when it was.
+///
+/// An operation holds an exclusive lock on `<dest>/lock` from `begin` until it commits or rolls back, so a journal
+/// is only ever recovered when no other operation is running on the destination.
+///
+/// A dry run journal only records what the operation would write and delete, it never touches the destination.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Journal {
+    pub operation: String,
+    pub started: DateTime<Utc>,
+    pub committed: bool,
+    /// final paths of the files written by the operation.
+    writes: Vec<PathBuf>,
+    /// files deleted by the operation.
+    removals: Vec<PathBuf>,
+    #[serde(skip)]
+    dest: PathBuf,
+    #[serde(skip)]
+    dry_run: bool,
+    /// size in bytes of every file written by the operation.
+    #[serde(skip)]
+    sizes: HashMap<PathBuf, u64>,
+    /// lock on the destination, released when the journal is dropped.
+    #[serde(skip)]
+    _lock: Option<Flock<fs::File>>,
+}
+
+impl Journal {
+    pub fn path(dest: &Path) -> PathBuf {
+        dest.join("journal.json")
+    }
+
+    pub fn lock_path(dest: &Path) -> PathBuf {
+        dest.join("lock")
+    }
+
+    /// Path a file is written to until the operation writing it commits.
+    pub fn pending_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".");
+        name.push(PENDING_EXTENSION);
+        PathBuf::from(name)
+    }
+
+    /// Start `operation` on the backup at `dest`, first cleaning up after any operation that did not finish.
+    /// Fails when another operation is in progress on `dest`.
+    pub fn begin(dest: &Path, operation: &str) -> Result<Self, SnapError> {
+        let lock = Self::lock(dest)?;
+
+        if let Some(message) = Self::recover_locked(dest)? {
+            println!("{message}");
+        }
+
+        let journal = Self {
+            operation: operation.to_string(),
+            started: Utc::now(),
+            committed: false,
+            writes: Vec::new(),
+            removals: Vec::new(),
+            dest: dest.to_path_buf(),
+            dry_run: false,
+            sizes: HashMap::new(),
+            _lock: Some(lock),
+        };
+        journal.save()?;
+
+        Ok(journal)
+    }
+
+    /// Journal of an operation that is only simulated: nothing is written to or deleted from `dest`,
+    /// not even the journal itself, and leftovers of an interrupted operation are left alone.
+    pub fn dry_run(dest: &Path, operation: &str) -> Self {
+        Self {
+            operation: operation.to_string(),
+            started: Utc::now(),
+            committed: false,
+            writes: Vec::new(),
+            removals: Vec::new(),
+            dest: dest.to_path_buf(),
+            dry_run: true,
+            sizes: HashMap::new(),
+            _lock: None,
+        }
+    }
+
+    /// Write `content` to the pending path of `path`. It is moved to `path` when the operation commits.
+    pub fn write(&mut self, path: &Path, content: &[u8]) -> io::Result<()> {
+        if !self.dry_run {
+            fs::write(Self::pending_path(path), content)?;
+        }
+
+        self.sizes.insert(path.to_path_buf(), content.len() as u64);
+
+        if !self.writes.iter().any(|written| written == path) {
+            self.writes.push(path.to_path_buf());
+        }
+
+        Ok(())
+    }
+
+    /// Delete `path` when the operation commits.
+    pub fn remove(&mut self, path: &Path) {
+        if !self.removals.iter().any(|removed| removed == path) {
+            self.removals.push(path.to_path_buf());
+        }
+    }
+
+    /// The path holding the current content of `path` for this operation, its pending path when it was written.
+    pub fn staged(&self, path: &Path) -> PathBuf {
+        if !self.dry_run && self.writes.iter().any(|written| written == path) {
+            Self::pending_path(path)
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    pub fn is_removed(&self, path: &Path) -> bool {
+        self.removals.iter().any(|removed| removed == path)
+    }
+
+    /// Final paths of the files written by the operation, in the order they were written.
+    pub fn writes(&self) -> &[PathBuf] {
+        &self.writes
+    }
+
+    /// Files deleted by the operation, in the order they were recorded.
+    pub fn removals(&self) -> &[PathBuf] {
+        &self.removals
+    }
+
+    /// Size in bytes of the file the operation wrote at `path`.
+    pub fn written_size(&self, path: &Path) -> Option<u64> {
+        self.sizes.get(path).copied()
+    }
+
+    /// Make every change of the operation permanent.
+    ///
+    /// Saving the journal as committed is the commit point: from then on a crash is recovered by finishing the operation.
+    pub fn commit(mut self) -> Result<(), SnapError> {
+        if self.dry_run {
+            return Ok(());
+        }
+
+        // pending files reach the disk before the journal marks them as committed.
+        self.sync_pending()?;
+
+        self.committed = true;
+        self.save()?;
+        self.apply()?;
+        self.sync_dirs()?;
+
+        fs::remove_file(Self::path(&self.dest))?;
+
+        Ok(())
+    }
+
+    /// Drop every change of the operation.
+    pub fn rollback(self) -> Result<(), SnapError> {
+        if self.dry_run {
+            return Ok(());
+        }
+
+        Self::discard_pending(&self.dest)?;
+        fs::remove_file(Self::path(&self.dest))?;
+
+        Ok(())
+    }
+
+    /// Take the exclusive lock on the backup at `dest`, held until the returned lock is dropped.
+    fn lock(dest: &Path) -> Result<Flock<fs::File>, SnapError> {
+        let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(Self::lock_path(dest))?;
+
+        Flock::lock(file, FlockArg::LockExclusiveNonblock).map_err(|(_, errno)| match errno {
+            Errno::EWOULDBLOCK => {
+                let message = format!("Another operation is in progress on {}, try again once it finished.", dest.display());
+                SnapError::IOError(io::Error::new(io::ErrorKind::WouldBlock, message))
+            },
+            errno => SnapError::IOError(errno.into()),
+        })
+    }
+
+    /// Clean up after an operation on the backup at `dest` that did not finish, if any.
+    /// Returns a message describing what was done. Fails when another operation is in progress on `dest`.
+    pub fn recover(dest: &Path) -> Result<Option<String>, SnapError> {
+        let _lock = Self::lock(dest)?;
+
+        Self::recover_locked(dest)
+    }
+
+    /// Same as `recover`, with the lock on `dest` already held.
+    fn recover_locked(dest: &Path) -> Result<Option<String>, SnapError> {
+        let path = Self::path(dest);
+        let tmp_path = path.with_extension("json.tmp");
+
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read(&path)?;
+        let journal = match serde_json::from_slice::<Journal>(&content) {
+            Ok(mut journal) => {
+                journal.dest = dest.to_path_buf();
+                journal
+            },
+            // a journal that was not fully written cannot have been committed.
+            Err(_) => {
+                Self::discard_pending(dest)?;
+                fs::remove_file(&path)?;
+                return Ok(Some("Removed the leftovers of an interrupted operation.".into()));
+            }
+        };
+
+        let started = journal.started.format("%Y-%m-%d %H:%M:%S");
+        let message = if journal.committed {
+            format!("Finished the interrupted {} started at {started}.", journal.operation)
+        } else {
+            format!("Rolled back the interrupted {} started at {started}.", journal.operation)
+        };
+
+        if journal.committed {
+            journal.apply()?;
+            journal.sync_dirs()?;
+            fs::remove_file(&path)?;
+        } else {
+            journal.rollback()?;
+        }
+
+        Ok(Some(message))
+    }
+
+    /// Move the pending files into place and delete the removed files. Safe to run again after a crash.
+    fn apply(&self) -> io::Result<()> {
+        for path in &self.writes {
+            let pending = Self::pending_path(path);
+
+            if pending.exists() {
+                fs::rename(&pending, path)?;
+            }
+        }
+
+        for path in &self.removals {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flush every pending file to disk, along with the directories holding them.
+    fn sync_pending(&self) -> io::Result<()> {
+        for path in &self.writes {
+            fs::File::open(Self::pending_path(path))?.sync_all()?;
+        }
+
+        self.sync_dirs()
+    }
+
+    /// Flush the directories holding the files of the operation to disk, so their renames and deletions survive a crash.
+    fn sync_dirs(&self) -> io::Result<()> {
+        let dirs: HashSet<&Path> = self.writes.iter()
+            .chain(&self.removals)
+            .filter_map(|path| path.parent())
+            .chain([self.dest.as_path()])
+            .collect();
+
+        for dir in dirs.into_iter().filter(|dir| dir.is_dir()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete every pending file of the destination. Only uncommitted operations leave pending files.
+    fn discard_pending(dest: &Path) -> io::Result<()> {
+        let dirs = [dest.to_path_buf(), dest.join("blobs"), dest.join("snapshot")];
+
+        for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+
+                if path.is_file() && path.extension() == Some(OsStr::new(PENDING_EXTENSION)) {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Save the journal through a temporary file so a crash never leaves a half written journal.
+    fn save(&self) -> io::Result<()> {
+        let path = Self::path(&self.dest);
+        let tmp_path = path.with_extension("json.tmp");
+
+        let json = serde_json::to_string_pretty(&self)?;
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(tmp_path, path)?;
+        fs::File::open(&self.dest)?.sync_all()
+    }
+}
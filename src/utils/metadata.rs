@@ -0,0 +1,100 @@
+use std::{collections::BTreeMap, fs, io, os::unix::fs::{MetadataExt, PermissionsExt}, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use nix::sys::{stat::{utimensat, UtimensatFlags}, time::TimeSpec};
+use serde::{Deserialize, Serialize};
+
+/// Permissions, ownership, timestamps and extended attributes of a file at backup time.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileMetadata {
+    /// permission bits, including setuid, setgid and sticky bits.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub accessed: SystemTime,
+    pub modified: SystemTime,
+    /// extended attributes by name, with hex encoded values.
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+/// What could not be re-applied while restoring the metadata of a file.
+#[derive(Debug, Default, PartialEq)]
+pub struct MetadataIssues {
+    /// the owner could not be changed, usually because the restore does not run as root.
+    pub owner: bool,
+    /// names of the extended attributes that could not be set.
+    pub xattrs: Vec<String>,
+}
+
+impl FileMetadata {
+    /// Read the metadata of the file at `path`, whose `fs::Metadata` is `metadata`.
+    ///
+    /// Extended attributes are left out on file systems that do not support them.
+    pub fn read(path: &Path, metadata: &fs::Metadata) -> io::Result<Self> {
+        let mut xattrs = BTreeMap::new();
+
+        if let Ok(names) = xattr::list(path) {
+            for name in names {
+                if let Some(value) = xattr::get(path, &name)? {
+                    xattrs.insert(name.to_string_lossy().to_string(), hex::encode(value));
+                }
+            }
+        }
+
+        Ok(Self {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            accessed: metadata.accessed()?,
+            modified: metadata.modified()?,
+            xattrs,
+        })
+    }
+
+    /// Whether `other` records the same permissions, ownership, modification time and extended attributes.
+    /// The access time is left out, reading a file to back it up changes it.
+    pub fn same_as(&self, other: &FileMetadata) -> bool {
+        (self.mode, self.uid, self.gid, self.modified, &self.xattrs) == (other.mode, other.uid, other.gid, other.modified, &other.xattrs)
+    }
+
+    /// Apply this metadata to the file at `path`, owned by `owner` as `(uid, gid)` or left to the current user when `None`.
+    /// A symlink at `path` gets the metadata itself, its target is left alone.
+    ///
+    /// Failing to change the owner or to set an extended attribute is reported instead of failing the restore,
+    /// since both need privileges the restoring user may not have.
+    pub fn apply(&self, path: &Path, owner: Option<(u32, u32)>) -> io::Result<MetadataIssues> {
+        let mut issues = MetadataIssues::default();
+        let is_symlink = fs::symlink_metadata(path)?.file_type().is_symlink();
+
+        for (name, value) in &self.xattrs {
+            let value = hex::decode(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+            if xattr::set(path, name, &value).is_err() {
+                issues.xattrs.push(name.clone());
+            }
+        }
+
+        // changing the owner clears the setuid and setgid bits, so it comes before the mode.
+        if let Some((uid, gid)) = owner {
+            match std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+                Ok(()) => {},
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => issues.owner = true,
+                Err(err) => return Err(err),
+            }
+        }
+
+        // symlinks have no permissions of their own.
+        if !is_symlink {
+            fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        }
+
+        utimensat(None, path, &to_timespec(self.accessed), &to_timespec(self.modified), UtimensatFlags::NoFollowSymlink)?;
+
+        Ok(issues)
+    }
+}
+
+/// `time` as a `TimeSpec`, times before the epoch are clamped to it.
+fn to_timespec(time: SystemTime) -> TimeSpec {
+    TimeSpec::from_duration(time.duration_since(UNIX_EPOCH).unwrap_or_default())
+}
@@ -1,14 +1,19 @@
-use std::{fs, io::{self, Write}, path::{Path, PathBuf}};
+use std::{fs, io::{self, Write}, path::{Component, Path, PathBuf}, sync::atomic::{AtomicBool, AtomicUsize, Ordering}, thread};
 
 use rpassword::prompt_password;
 
-use crate::{compress::{self, CompressionEngine}, crypto::password::{PasswordError, PasswordPolicy}, utils::{config::Config, error::SnapError, registry::{BackupEntry, BackupRegistry}}};
+use crate::{compress::{self, CompressionEngine}, crypto::{self, keyslot::KeyStore, password::{PasswordError, PasswordPolicy}}, utils::{config::Config, error::SnapError, registry::{BackupEntry, BackupRegistry}}};
 
+pub mod chunker;
 pub mod config;
 pub mod config_utils;
 pub mod error;
 pub mod gc;
+pub mod journal;
+pub mod metadata;
 pub mod registry;
+pub mod rules;
+pub mod selector;
 pub mod snapshot;
 
 /// Generate a compression engine from the algorithm information provided.
@@ -52,8 +57,9 @@ pub fn read_password() -> Result<String, PasswordError> {
 
     let policy = PasswordPolicy::default();
 
+    // the policy goes to stderr so commands like `snapsafe cat` keep a clean stdout.
     let message = policy.generate_policy();
-    println!("{message}");
+    eprintln!("{message}");
     let pwd = prompt_password("Enter Password: ")?;
 
     if let Err(err) = policy.validate(&pwd){
@@ -63,6 +69,68 @@ pub fn read_password() -> Result<String, PasswordError> {
     Ok(pwd)
 }
 
+/// Read a new password for a key slot, from `SNAPSAFE_NEW_PASSWORD` or by prompting twice.
+pub fn read_new_password() -> Result<String, PasswordError> {
+    let policy = PasswordPolicy::default();
+
+    if let Ok(pwd) = std::env::var("SNAPSAFE_NEW_PASSWORD") {
+        policy.validate(&pwd)?;
+        return Ok(pwd);
+    }
+
+    let message = policy.generate_policy();
+    println!("{message}");
+    let pwd = prompt_password("Enter New Password: ")?;
+    policy.validate(&pwd)?;
+
+    let confirmation = prompt_password("Confirm New Password: ")?;
+    if confirmation != pwd {
+        return Err(PasswordError::InvalidFormat("The passwords entered do not match".into()));
+    }
+
+    Ok(pwd)
+}
+
+/// Check `password` against the password hash the backup `entry` was created with.
+///
+/// Only backups created before key slots carry such a hash.
+pub fn verify_password(entry: &BackupEntry, password: &str) -> Result<(), SnapError> {
+    match entry.password.verify(password) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(SnapError::Password(PasswordError::IncorrectPassword)),
+        Err(err) => Err(SnapError::Password(err)),
+    }
+}
+
+/// Unlock the master key of the backup stored at `dest` with `password`.
+///
+/// The password is checked against the key slots in `<dest>/keys.json`.
+/// A backup made before key slots has no key store: its key is derived from the password and `<dest>/key_salt`.
+/// That key is kept as the master key and wrapped in a first key slot, so none of the existing blobs change.
+/// The key store is only saved with `persist`: commands that write to the backup (`backup`, `delete` and `key`)
+/// migrate it, read-only commands and dry runs leave the backup as it is.
+pub fn unlock_key(dest: &Path, password: &str, entry: Option<&BackupEntry>, persist: bool) -> Result<[u8; 32], SnapError> {
+    if let Some(store) = KeyStore::load(dest)? {
+        let (_, key) = store.unlock(password)?;
+        return Ok(key);
+    }
+
+    match entry {
+        Some(ent) if dest.join("key_salt").exists() => {
+            verify_password(ent, password)?;
+
+            let salt = get_salt(dest);
+            let key = crypto::derive_key(password, &salt);
+            if persist {
+                KeyStore::new(&key, password).save(dest)?;
+            }
+
+            Ok(key)
+        },
+        _ => Err(SnapError::KeyStore("No key store found for this backup.".into())),
+    }
+}
+
 pub fn get_registry() -> BackupRegistry {
     
     let bkup_registry = 
@@ -124,22 +192,102 @@ pub fn prompt_for_input(message: &str) -> Option<String> {
     }
 }
 
-pub fn get_nth_recent_json_snapshot(nth: usize, dir: &Path) -> io::Result<Option<String>> {
-    let mut entries: Vec<_> = fs::read_dir(dir)?
+/// List the json snapshot manifests in `dir`, the most recent one first.
+pub fn list_json_snapshots(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
         .filter_map(Result::ok)
         .filter(|e| {
             e.path().extension().map(|ext| ext == "json").unwrap_or(false)
             && e.metadata().map(|m| m.is_file()).unwrap_or(false)
         })
-        .filter_map(|entry| {
-            entry.metadata().ok().and_then(|meta| {
-                meta.modified().ok().map(|modified| (modified, entry.path()))
-            })
-        }).collect();
+        .map(|entry| entry.path())
+        .collect();
 
-    entries.sort_by(|a, b| b.0.cmp(&a.0));
+    // snapshot ids are UTC timestamps, so sorting by id keeps the order when the backup is copied
+    // and file modification times change.
+    entries.sort_by(|a, b| b.file_stem().cmp(&a.file_stem()));
+
+    Ok(entries)
+}
+
+/// Number of worker threads `jobs` asks for, 0 meaning one per available CPU.
+pub fn worker_count(jobs: usize) -> usize {
+    if jobs > 0 {
+        return jobs;
+    }
+
+    thread::available_parallelism().map(|count| count.get()).unwrap_or(1)
+}
+
+/// Run `task` on every item of `items` with up to `jobs` worker threads, see `worker_count`.
+/// Results are returned in the order of `items`, whatever order the workers finish in.
+///
+/// Workers stop taking new items after the first error, the error of the first failing item is returned.
+pub fn run_parallel<T, R, E>(items: &[T], jobs: usize, task: impl Fn(&T) -> Result<R, E> + Sync) -> Result<Vec<R>, E>
+where
+    T: Sync,
+    R: Send,
+    E: Send,
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let workers = worker_count(jobs).min(items.len()).max(1);
+
+    let mut results: Vec<(usize, Result<R, E>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers).map(|_| scope.spawn(|| {
+            let mut done = Vec::new();
+
+            while !failed.load(Ordering::Relaxed) {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else { break };
+
+                let result = task(item);
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                done.push((index, result));
+            }
+
+            done
+        })).collect();
+
+        handles.into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect()
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Find the manifest of the snapshot with id `id`, the file stem of its json file, in `dir`.
+pub fn find_json_snapshot(id: &str, dir: &Path) -> Option<PathBuf> {
+    // ids come from the command line: anything but a snapshot id could point outside `dir`.
+    if !selector::is_snapshot_id(id) {
+        return None;
+    }
+
+    let path = dir.join(format!("{id}.json"));
+
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Drop `.` components and any leading `/` of a user provided path so it can be compared with
+/// the relative paths stored in a snapshot manifest.
+pub fn normalize_snapshot_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+pub fn get_nth_recent_json_snapshot(nth: usize, dir: &Path) -> io::Result<Option<String>> {
+    let entries = list_json_snapshots(dir)?;
 
-    Ok(entries.get(nth).map(|(_, path)| path.to_string_lossy().to_string()))
+    Ok(entries.get(nth).map(|path| path.to_string_lossy().to_string()))
 }
 
 pub fn get_salt(dir: &Path) -> Vec<u8> {
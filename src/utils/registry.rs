@@ -12,6 +12,9 @@ pub struct BackupEntry {
     pub timestamp: DateTime<Utc>,
     pub origin_path: PathBuf,
     pub backup_path: PathBuf,
+    /// Password hash of backups created before key slots, used once to migrate them to a key store.
+    /// Backups with a key store keep the `<unset>` default.
+    #[serde(default)]
     pub password: Password,
     pub snapshot_count: usize,
     pub compression_algorithm: String,
@@ -38,7 +41,7 @@ pub struct BackupRegistry {
 }
 
 impl BackupEntry {
-    pub fn new(timestamp: DateTime<Utc>, src: PathBuf, target: PathBuf, password: &Password, compression: String) -> Self {
+    pub fn new(timestamp: DateTime<Utc>, src: PathBuf, target: PathBuf, compression: String) -> Self {
         let id = Uuid::new_v4().to_string();
 
         Self { 
@@ -46,7 +49,7 @@ impl BackupEntry {
             timestamp, 
             origin_path: src, 
             backup_path: target, 
-            password: password.clone(), 
+            password: Password::default(), 
             snapshot_count: 1,
             compression_algorithm: compression,
         }
@@ -0,0 +1,173 @@
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{chunker::DEFAULT_CHUNK_SIZE, error::SnapError};
+
+/// Name of the ignore file read in every directory of the source.
+pub const IGNORE_FILE: &str = ".snapsafeignore";
+
+/// Name of the file marking a cache directory, see <https://bford.info/cachedir/>.
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// First bytes of a valid `CACHEDIR.TAG`.
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// The rules deciding which files of the source a backup skips and how it reads them, recorded in the snapshot it creates.
+///
+/// Every rule uses gitignore syntax. `--include` rules win over everything else, then `--exclude` rules,
+/// then the ignore files found in the source, the one in the deepest directory first.
+/// As with git, a file cannot be included again once a directory above it is excluded.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BackupRules {
+    /// patterns of the files to skip, from `--exclude`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// patterns of the files to back up even when another rule skips them, from `--include`.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// whether `.gitignore` files were read along with `.snapsafeignore` files.
+    #[serde(default)]
+    pub gitignore: bool,
+    /// whether directories holding a `CACHEDIR.TAG` were skipped.
+    #[serde(default)]
+    pub exclude_caches: bool,
+    /// whether symlinks were followed and backed up as the files and directories they point to.
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// ignore files read from the source, relative to it, with the patterns they held at backup time.
+    #[serde(default)]
+    pub ignore_files: BTreeMap<PathBuf, Vec<String>>,
+    /// average size of the chunks files were split into, 0 for manifests written before files were chunked.
+    #[serde(default)]
+    pub chunk_size: u32,
+    /// number of files read at once, 0 for one per available CPU. Not recorded, it does not change the snapshot.
+    #[serde(skip)]
+    pub jobs: usize,
+    /// read every file again, even the ones whose change key shows they did not change. Not recorded either.
+    #[serde(skip)]
+    pub force_rehash: bool,
+}
+
+impl BackupRules {
+    pub fn new(exclude: Vec<String>, include: Vec<String>, gitignore: bool, exclude_caches: bool) -> Self {
+        Self { exclude, include, gitignore, exclude_caches, follow_symlinks: false, ignore_files: BTreeMap::new(), chunk_size: DEFAULT_CHUNK_SIZE, jobs: 0, force_rehash: false }
+    }
+}
+
+/// Applies a set of `BackupRules` while walking the source at `root`, reading ignore files as directories are entered.
+pub struct RuleMatcher {
+    root: PathBuf,
+    rules: BackupRules,
+    include: Gitignore,
+    exclude: Gitignore,
+    /// ignore files of every directory seen so far, `.snapsafeignore` first.
+    dirs: HashMap<PathBuf, Vec<Gitignore>>,
+}
+
+impl RuleMatcher {
+    pub fn new(root: &Path, rules: &BackupRules) -> Result<Self, SnapError> {
+        Ok(Self {
+            root: root.to_path_buf(),
+            rules: BackupRules { ignore_files: BTreeMap::new(), ..rules.clone() },
+            include: build_matcher(root, &rules.include)?,
+            exclude: build_matcher(root, &rules.exclude)?,
+            dirs: HashMap::new(),
+        })
+    }
+
+    /// Whether the file or directory at `path`, below the root, is skipped by the rules.
+    pub fn is_excluded(&mut self, path: &Path, is_dir: bool) -> Result<bool, SnapError> {
+        if path == self.root {
+            return Ok(false);
+        }
+
+        if self.include.matched(path, is_dir).is_ignore() {
+            return Ok(false);
+        }
+
+        if self.exclude.matched(path, is_dir).is_ignore() {
+            return Ok(true);
+        }
+
+        if is_dir && self.rules.exclude_caches && is_cache_dir(path) {
+            return Ok(true);
+        }
+
+        for dir in path.ancestors().skip(1) {
+            for matcher in self.ignore_files_of(dir)? {
+                let matched = matcher.matched(path, is_dir);
+
+                if matched.is_ignore() {
+                    return Ok(true);
+                }
+                if matched.is_whitelist() {
+                    return Ok(false);
+                }
+            }
+
+            if dir == self.root {
+                break;
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// The rules that were applied, with the content of every ignore file read.
+    pub fn into_rules(self) -> BackupRules {
+        self.rules
+    }
+
+    fn ignore_files_of(&mut self, dir: &Path) -> Result<&[Gitignore], SnapError> {
+        if !self.dirs.contains_key(dir) {
+            let mut names = vec![IGNORE_FILE];
+            if self.rules.gitignore {
+                names.push(".gitignore");
+            }
+
+            let mut matchers = Vec::new();
+            for name in names {
+                let path = dir.join(name);
+
+                if !path.is_file() {
+                    continue;
+                }
+
+                let patterns: Vec<String> = fs::read_to_string(&path)?.lines()
+                    .map(|line| line.trim_end().to_string())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .collect();
+
+                matchers.push(build_matcher(dir, &patterns)?);
+
+                let rel_path = path.strip_prefix(&self.root).unwrap_or(&path).to_path_buf();
+                self.rules.ignore_files.insert(rel_path, patterns);
+            }
+
+            self.dirs.insert(dir.to_path_buf(), matchers);
+        }
+
+        Ok(&self.dirs[dir])
+    }
+}
+
+/// Whether `dir` holds a `CACHEDIR.TAG` starting with the standard signature.
+pub fn is_cache_dir(dir: &Path) -> bool {
+    fs::read(dir.join(CACHEDIR_TAG))
+        .map(|content| content.starts_with(CACHEDIR_SIGNATURE))
+        .unwrap_or(false)
+}
+
+fn build_matcher(root: &Path, patterns: &[String]) -> Result<Gitignore, SnapError> {
+    let mut builder = GitignoreBuilder::new(root);
+
+    for pattern in patterns {
+        if let Err(err) = builder.add_line(None, pattern) {
+            return Err(SnapError::Backup(format!("Invalid rule '{pattern}': {err}")));
+        }
+    }
+
+    builder.build().map_err(|err| SnapError::Backup(format!("Invalid rules: {err}")))
+}
@@ -0,0 +1,127 @@
+use std::{fmt, io, path::{Path, PathBuf}, str::FromStr};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+
+use crate::utils;
+
+/// Formats of snapshot ids, the file stem of a manifest. Older backups named manifests to the second.
+const SNAPSHOT_ID_FORMATS: [&str; 2] = ["%Y-%m-%dT%H-%M-%S-%6f", "%Y-%m-%dT%H-%M-%S"];
+
+/// Formats accepted by `--at`, interpreted as UTC like snapshot ids.
+const DATETIME_FORMATS: [&str; 4] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"];
+
+/// Identifies one snapshot of a backup, shared by every command that reads snapshots.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotSelector {
+    /// the snapshot with this id, as shown by `snapsafe snapshots`.
+    Id(String),
+    /// the snapshot `n` snapshots before the latest one, `latest~0` being the latest.
+    Latest(usize),
+    /// the newest snapshot taken at or before this time.
+    At(DateTime<Utc>),
+}
+
+impl Default for SnapshotSelector {
+    fn default() -> Self {
+        SnapshotSelector::Latest(0)
+    }
+}
+
+impl SnapshotSelector {
+    /// Parse the value of `--at`: RFC 3339, `YYYY-MM-DD HH:MM[:SS]` in UTC, or a plain date meaning the end of that day.
+    pub fn at(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+
+        if let Ok(time) = DateTime::parse_from_rfc3339(input) {
+            return Ok(SnapshotSelector::At(time.with_timezone(&Utc)));
+        }
+
+        for format in DATETIME_FORMATS {
+            if let Ok(time) = NaiveDateTime::parse_from_str(input, format) {
+                return Ok(SnapshotSelector::At(time.and_utc()));
+            }
+        }
+
+        if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+            let end_of_day = NaiveTime::from_hms_micro_opt(23, 59, 59, 999_999).unwrap();
+            return Ok(SnapshotSelector::At(date.and_time(end_of_day).and_utc()));
+        }
+
+        Err(format!("Invalid date and time '{input}', expected e.g. \"2024-05-01 18:30:00\" or \"2024-05-01\"."))
+    }
+
+    /// Find the manifest of the selected snapshot in `snapshot_dir`, `None` when there is no such snapshot.
+    pub fn resolve(&self, snapshot_dir: &Path) -> io::Result<Option<PathBuf>> {
+        if !snapshot_dir.try_exists()? {
+            return Ok(None);
+        }
+
+        let path = match self {
+            SnapshotSelector::Id(id) => utils::find_json_snapshot(id, snapshot_dir),
+            SnapshotSelector::Latest(n) => utils::list_json_snapshots(snapshot_dir)?.into_iter().nth(*n),
+            SnapshotSelector::At(time) => utils::list_json_snapshots(snapshot_dir)?
+                .into_iter()
+                .find(|path| snapshot_time(path).map(|taken| taken <= *time).unwrap_or(false)),
+        };
+
+        Ok(path)
+    }
+}
+
+impl FromStr for SnapshotSelector {
+    type Err = String;
+
+    /// Parse `latest`, `latest~N` or a snapshot id.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+
+        if input.is_empty() {
+            return Err("Snapshot selector cannot be empty.".into());
+        }
+
+        if input == "latest" {
+            return Ok(SnapshotSelector::Latest(0));
+        }
+
+        if let Some(n) = input.strip_prefix("latest~") {
+            return n.parse::<usize>()
+                .map(SnapshotSelector::Latest)
+                .map_err(|_| format!("Invalid snapshot selector '{input}', expected latest~N with N a number."));
+        }
+
+        if !is_snapshot_id(input) {
+            return Err(format!("Invalid snapshot selector '{input}', expected a snapshot id, latest or latest~N."));
+        }
+
+        Ok(SnapshotSelector::Id(input.to_string()))
+    }
+}
+
+impl fmt::Display for SnapshotSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotSelector::Id(id) => write!(f, "{id}"),
+            SnapshotSelector::Latest(0) => write!(f, "latest"),
+            SnapshotSelector::Latest(n) => write!(f, "latest~{n}"),
+            SnapshotSelector::At(time) => write!(f, "at {}", time.format("%Y-%m-%d %H:%M:%S")),
+        }
+    }
+}
+
+/// Time a snapshot was taken, read from the id of its manifest at `path`.
+pub fn snapshot_time(path: &Path) -> Option<DateTime<Utc>> {
+    let id = path.file_stem()?.to_str()?;
+
+    parse_snapshot_id(id)
+}
+
+/// Whether `id` has the form of a snapshot id, so it names a manifest and nothing else.
+pub fn is_snapshot_id(id: &str) -> bool {
+    parse_snapshot_id(id).is_some()
+}
+
+fn parse_snapshot_id(id: &str) -> Option<DateTime<Utc>> {
+    SNAPSHOT_ID_FORMATS.iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(id, format).ok())
+        .map(|time| time.and_utc())
+}
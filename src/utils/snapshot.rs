@@ -1,15 +1,35 @@
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+use hmac::Mac;
 use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
-use std::{collections::HashMap, fs, io::{self, Write}, path::{Path, PathBuf}, time::SystemTime};
+use std::{collections::{BTreeMap, HashMap}, fs, io::{self, BufReader, Read, Write}, os::unix::fs::{FileTypeExt, MetadataExt}, path::{Path, PathBuf}, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use crate::{compress::CompressionEngine, crypto, utils::{error::SnapError, gc::GarbageCollector}};
+use crate::{compress::CompressionEngine, crypto::{self, keyslot::KeyStore, stream::{self, DecryptReader}}, utils::{self, chunker, error::SnapError, gc::GarbageCollector, journal::Journal, metadata::FileMetadata, rules::{BackupRules, RuleMatcher}}};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Snapshot {
     pub timestamp: DateTime<Utc>, // update to timestamp type
-    pub files: HashMap<PathBuf, FileEntry> // relative file_path -> filehash
+    /// relative file_path -> filehash, written in path order so the same files always give the same manifest.
+    #[serde(serialize_with = "serialize_sorted")]
+    pub files: HashMap<PathBuf, FileEntry>,
+    /// rules the backup skipped files with, empty for manifests written before rules were recorded.
+    #[serde(default)]
+    pub rules: BackupRules,
+}
+
+/// Version of the format of encrypted manifests, written in their header.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// What a manifest file holds: the snapshot encrypted with the repository key behind a small plaintext header.
+/// Manifests written before they were encrypted hold the snapshot itself.
+#[derive(Debug, Deserialize, Serialize)]
+struct EncryptedManifest {
+    version: u32,
+    /// hex encoded nonce the snapshot was encrypted with.
+    nonce: String,
+    /// hex encoded snapshot, serialized as json and encrypted in segments, see `crypto::stream`.
+    ciphertext: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -17,64 +37,293 @@ pub struct FileEntry {
     pub hash: String,
     pub nonce: [u8; 12],
     pub modified: SystemTime,
-    pub isupdated: bool
+    pub isupdated: bool,
+    /// size of the original file in bytes, 0 for manifests written before sizes were recorded.
+    #[serde(default)]
+    pub size: u64,
+    /// permissions, ownership and timestamps of the original file, `None` for manifests written before they were recorded.
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>,
+    /// what the entry is, only regular files have a blob.
+    #[serde(default)]
+    pub kind: EntryKind,
+    /// first path of the snapshot sharing this file's inode, whose blob this entry shares.
+    /// `None` for that first path and for files with a single link.
+    #[serde(default)]
+    pub hard_link: Option<PathBuf>,
+    /// blobs holding the content of the file in order, `hash` is then the hash of their hashes.
+    /// `None` for manifests written before files were chunked, whose content is the single blob `hash`.
+    #[serde(default)]
+    pub chunks: Option<Vec<Chunk>>,
+    /// sha256 of the content of the file, checked once its blobs are decompressed.
+    /// `None` for entries other than regular files and for manifests written before it was recorded.
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// what the next backup compares the file with to skip it without reading it when it did not change.
+    /// `None` for entries other than regular files and for manifests written before it was recorded.
+    #[serde(default)]
+    pub change_key: Option<ChangeKey>,
+}
+
+/// Size, modification and status change times and inode of a regular file when it was read.
+///
+/// Writing to a file changes its modification time, and changing its metadata or replacing it its status change time
+/// or its inode. A file whose key did not change since the last backup is assumed to have the same content.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ChangeKey {
+    pub size: u64,
+    pub modified: SystemTime,
+    pub changed: SystemTime,
+    pub inode: u64,
+}
+
+impl ChangeKey {
+    pub fn of(metadata: &fs::Metadata) -> io::Result<Self> {
+        // the status change time is only exposed as seconds and nanoseconds since the epoch.
+        let changed = u64::try_from(metadata.ctime())
+            .map(|secs| UNIX_EPOCH + Duration::new(secs, metadata.ctime_nsec() as u32))
+            .unwrap_or(UNIX_EPOCH);
+
+        Ok(Self { size: metadata.len(), modified: metadata.modified()?, changed, inode: metadata.ino() })
+    }
+}
+
+/// A piece of the content of a file, stored in the blob named after `hash`.
+/// Chunks with the same content share one blob, across files and snapshots.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Chunk {
+    /// name of the blob, a hash of the chunk as told by `naming`.
+    pub hash: String,
+    pub nonce: [u8; 12],
+    /// size of the chunk before compression.
+    pub size: u64,
+    /// whether the blob is encrypted in segments, see `crypto::stream`. Blobs written before are a single AES-GCM message.
+    #[serde(default)]
+    pub segmented: bool,
+    #[serde(default)]
+    pub naming: BlobNaming,
+}
+
+/// How the name of a blob is derived from the chunk it holds.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlobNaming {
+    /// sha256 of the compressed chunk, for blobs written before they were named after their content.
+    #[default]
+    Compressed,
+    /// sha256 of the chunk itself, for blobs written before names were keyed.
+    Plaintext,
+    /// HMAC-SHA256 of the chunk itself keyed with `crypto::blob_mac`, so the same content has the same name
+    /// whatever the compression settings, and names cannot be matched against known files without the key.
+    Keyed,
+}
+
+/// Kind of a `FileEntry`, manifests written before kinds were recorded only hold regular files.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntryKind {
+    #[default]
+    File,
+    Dir,
+    Symlink { target: PathBuf },
+    Fifo,
+    CharDevice { rdev: u64 },
+    BlockDevice { rdev: u64 },
+}
+
+impl EntryKind {
+    /// Kind of the entry at `path` with `metadata`, `None` for sockets which cannot be backed up.
+    pub fn of(path: &Path, metadata: &fs::Metadata) -> io::Result<Option<Self>> {
+        let file_type = metadata.file_type();
+
+        let kind = if file_type.is_file() {
+            EntryKind::File
+        } else if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink { target: fs::read_link(path)? }
+        } else if file_type.is_fifo() {
+            EntryKind::Fifo
+        } else if file_type.is_char_device() {
+            EntryKind::CharDevice { rdev: metadata.rdev() }
+        } else if file_type.is_block_device() {
+            EntryKind::BlockDevice { rdev: metadata.rdev() }
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(kind))
+    }
 }
 
 impl Snapshot {
-    pub fn create(src: &Path, target: &Path, key: &[u8], latest_json_path: Option<&PathBuf>, engine: Box<dyn CompressionEngine>) -> Result<Self, SnapError> {
+    /// New blobs are written through `journal`, so they only appear in `target` once the backup commits.
+    /// Files and directories skipped by `rules` are left out, see `BackupRules`.
+    pub fn create(src: &Path, target: &Path, key: &[u8], latest_json_path: Option<&PathBuf>, engine: Box<dyn CompressionEngine>, rules: &BackupRules, journal: &mut Journal) -> Result<Self, SnapError> {
         let mut files = HashMap::<PathBuf, FileEntry>::new();
         let mut old_files = HashMap::<PathBuf, FileEntry>::new();
+        // directories, symlinks, special files and hard links that are new or changed since the last snapshot,
+        // and files whose content is unchanged but whose metadata or change key is not.
+        let mut changed_entries = 0;
+        // first path seen of every (device, inode) with more than one link.
+        let mut link_groups = HashMap::<(u64, u64), PathBuf>::new();
+        // paths of hard links with the first path of their group, resolved once every file was read.
+        let mut links = Vec::<(PathBuf, PathBuf)>::new();
+        // regular files that changed or are new, with their metadata, change key and entry in the last snapshot.
+        let mut to_read = Vec::<(PathBuf, PathBuf, FileMetadata, ChangeKey, Option<&FileEntry>)>::new();
 
         // get the latest json for this target path.
         let last_state = if let Some(json_path) = latest_json_path {
-            Some(Snapshot::from_json_to_snapshot(json_path.as_path())?)
+            Some(Snapshot::from_json_to_snapshot(json_path.as_path(), key)?)
         } else {
             None
         };
         let last_state = last_state.as_ref();
 
-        for entry in WalkDir::new(src).into_iter().filter_map(Result::ok) {
+        chunker::validate_chunk_size(rules.chunk_size)?;
+
+        // chunks with the same content share one blob, which must keep the nonce and format it was encrypted with.
+        // Any snapshot of the destination may hold a chunk, not only the latest one.
+        let mut known_blobs = HashMap::<String, Chunk>::new();
+        if let Some(snapshot_dir) = latest_json_path.and_then(|path| path.parent()) {
+            for manifest in utils::list_json_snapshots(snapshot_dir)? {
+                let snapshot = Snapshot::from_json_to_snapshot(&manifest, key)?;
+                known_blobs.extend(snapshot.files.values().flat_map(FileEntry::blobs).map(|chunk| (chunk.hash.clone(), chunk)));
+            }
+        }
+
+        let mut matcher = RuleMatcher::new(src, rules)?;
+        let mut rule_error = None;
+
+        // excluded directories are not entered at all. Walking in name order makes the first path of a hard link group stable.
+        let walker = WalkDir::new(src).follow_links(rules.follow_symlinks).sort_by_file_name().into_iter().filter_entry(|entry| {
+            match matcher.is_excluded(entry.path(), entry.file_type().is_dir()) {
+                Ok(excluded) => !excluded,
+                Err(err) => {
+                    rule_error.get_or_insert(err);
+                    false
+                }
+            }
+        });
+
+        for entry in walker.filter_map(Result::ok) {
             let path = entry.path();
             let metadata = entry.metadata()?;
 
-            if path.is_file() {
+            if entry.depth() == 0 {
+                continue;
+            }
+
+            let kind = match EntryKind::of(path, &metadata)? {
+                Some(kind) => kind,
+                None => continue,
+            };
+
+            if kind != EntryKind::File {
+                let rel_path = path.strip_prefix(src).unwrap().to_path_buf();
+                let file_metadata = FileMetadata::read(path, &metadata)?;
+                let prev_state = last_state.and_then(|snap| snap.files.get(&rel_path));
+
+                if prev_state.is_none_or(|f| f.kind != kind || !metadata_unchanged(f, &file_metadata)) {
+                    changed_entries += 1;
+                }
+
+                let file = FileEntry {
+                    hash: String::new(), nonce: [0u8; 12], modified: metadata.modified()?, isupdated: false, size: 0,
+                    metadata: Some(file_metadata), kind, hard_link: None, chunks: None, digest: None, change_key: None,
+                };
+                old_files.insert(rel_path, file);
+            } else if let Some(first) = link_groups.get(&(metadata.dev(), metadata.ino())) {
                 let rel_path = path.strip_prefix(src).unwrap().to_path_buf();
-                let content = fs::read(path)?;
-                let content = engine.compress(&content)?;
-                let hash = Sha256::digest(&content);
-                
+                links.push((rel_path, first.clone()));
+            } else {
+                let rel_path = path.strip_prefix(src).unwrap().to_path_buf();
+
+                if metadata.nlink() > 1 {
+                    link_groups.insert((metadata.dev(), metadata.ino()), rel_path.clone());
+                }
+
+                let change_key = ChangeKey::of(&metadata)?;
+                let file_metadata = FileMetadata::read(path, &metadata)?;
+
                 let prev_state = match last_state {
-                    Some(snap) => snap.files.get(&rel_path),
+                    Some(snap) => snap.files.get(&rel_path).filter(|f| f.kind == EntryKind::File),
                     None => None
                 };
 
-
+                // a file whose change key is the one recorded by the last snapshot is not opened at all.
                 match prev_state {
-                    Some(f) if metadata.modified()? == f.modified => {
+                    Some(f) if !rules.force_rehash && f.change_key.as_ref() == Some(&change_key) => {
                         let mut file = f.clone();
                         file.isupdated = false;
-                        old_files.insert(rel_path, file);
-                    }
-                    Some(file_entry) if file_entry.hash == format!("{:x}", &hash) => {
-                        let mut file = file_entry.clone();
-                        file.isupdated = false;
+                        file.metadata = Some(file_metadata);
+                        file.hard_link = None;
                         old_files.insert(rel_path, file);
                     },
-                    _ => {
-                        let (ciphertext, nonce) = crypto::encrypt_file_bytes(&content, key);
+                    _ => to_read.push((path.to_path_buf(), rel_path, file_metadata, change_key, prev_state)),
+                }
+            }
+        }
+
+        if let Some(err) = rule_error {
+            return Err(err);
+        }
+
+        let known_blobs = Mutex::new(known_blobs);
+        let journal = Mutex::new(journal);
 
-                        let hash_hex = format!("{:x}", hash);
-                        let blob_path = target.join(&hash_hex);
+        // files are read, compressed, hashed and encrypted by `rules.jobs` workers at once.
+        let read = utils::run_parallel(&to_read, rules.jobs, |(path, _, _, _, _)| {
+            read_chunks(path, rules.chunk_size, engine.as_ref(), key, target, &known_blobs, &journal)
+        })?;
 
-                        fs::write(&blob_path, ciphertext)?;
+        for ((_, rel_path, file_metadata, change_key, prev_state), (chunks, size, digest)) in to_read.into_iter().zip(read) {
+            let hash = chunk_list_hash(chunks.iter().map(|chunk| chunk.hash.as_str()));
 
-                        files.insert(rel_path, FileEntry { hash: hash_hex, nonce, modified: SystemTime::now(), isupdated: true });
+            match prev_state {
+                // the content did not change, the new metadata and change key still make a new snapshot,
+                // so the next backup skips the file. The chunks just read are kept: under another chunk size
+                // they differ from the recorded ones, and their blobs would be left unreferenced otherwise.
+                Some(file_entry) if file_entry.digest.as_ref() == Some(&digest) => {
+                    if !metadata_unchanged(file_entry, &file_metadata) || file_entry.change_key.as_ref() != Some(&change_key) {
+                        changed_entries += 1;
                     }
+
+                    let mut file = file_entry.clone();
+                    file.hash = hash;
+                    file.chunks = Some(chunks);
+                    file.isupdated = false;
+                    file.size = size;
+                    file.modified = change_key.modified;
+                    file.metadata = Some(file_metadata);
+                    file.hard_link = None;
+                    file.change_key = Some(change_key);
+                    old_files.insert(rel_path, file);
+                },
+                _ => {
+                    files.insert(rel_path, FileEntry {
+                        hash, nonce: [0u8; 12], modified: change_key.modified, isupdated: true, size, metadata: Some(file_metadata),
+                        kind: EntryKind::File, hard_link: None, chunks: Some(chunks), digest: Some(digest), change_key: Some(change_key),
+                    });
                 }
             }
         }
 
-        if files.is_empty() {
+        // the content of a hard link was stored with the first path of its group.
+        for (rel_path, first) in links {
+            let mut file = files.get(&first).or_else(|| old_files.get(&first)).unwrap().clone();
+            file.isupdated = false;
+            file.hard_link = Some(first);
+
+            let prev_state = last_state.and_then(|snap| snap.files.get(&rel_path));
+            if prev_state.map(|f| (&f.hash, &f.hard_link)) != Some((&file.hash, &file.hard_link)) {
+                changed_entries += 1;
+            }
+
+            old_files.insert(rel_path, file);
+        }
+
+        if files.is_empty() && changed_entries == 0 {
             return Err(SnapError::Backup("No File changes and hence backup aborted.".to_string()));
         }
         else {
@@ -82,24 +331,27 @@ impl Snapshot {
         }
         
         Ok(
-            Self{ timestamp: Utc::now(), files }
+            Self{ timestamp: Utc::now(), files, rules: matcher.into_rules() }
         )
     }
 
-    pub fn save(&self, snapshot_dir: &Path, gc: &mut GarbageCollector) -> io::Result<()> {
-        let safe_timestamp = self.timestamp.format("%Y-%m-%dT%H-%M-%S").to_string();
+    /// Write the manifest of this snapshot, encrypted with `key`, through `journal` and register its files with `gc`,
+    /// whose pruning of older versions is recorded in the same journal. Blobs of pruned versions are only
+    /// removed when no other file of a snapshot still uses them.
+    pub fn save(&self, snapshot_dir: &Path, key: &[u8], gc: &mut GarbageCollector, journal: &mut Journal) -> io::Result<()> {
+        let safe_timestamp = self.timestamp.format("%Y-%m-%dT%H-%M-%S-%6f").to_string();
         let file_path = snapshot_dir.join(format!("{safe_timestamp}.json"));
 
         // let mut gc = gc::GarbageCollector::new(blobs_dir.clone(), 3);
         
         if !&self.files.is_empty() {
-            for (path, file_entry) in &self.files {
-                gc.register_file(&path, &file_entry.hash, &file_path)?;
+            for (path, file_entry) in self.files.iter().filter(|(_, entry)| entry.has_blob()) {
+                gc.register_file(&path, &file_entry.hash, &file_path, key, journal)?;
             }
 
-            let mut file = fs::File::create(&file_path)?;
-            let json = serde_json::to_string_pretty(&self)?;
-            file.write_all(json.as_bytes())?;
+            journal.write(&file_path, &self.to_manifest(key)?)?;
+
+            gc.remove_unreferenced(snapshot_dir, self, key, journal)?;
         }
         else {
             println!("Nothing to add to json, state did not change for any file");
@@ -108,30 +360,340 @@ impl Snapshot {
         Ok(())
     }
 
-    pub fn save_snapshot(&self, file_path: &Path) -> io::Result<()> {
-        let parent = file_path.parent();
+    /// Read the manifest at `json_path`, decrypted with `key`.
+    ///
+    /// Plaintext manifests written before manifests were encrypted are only read from backups none of whose
+    /// manifests is encrypted yet, and whose key store does not mark them as encrypted under a MAC keyed from `key`.
+    pub fn from_json_to_snapshot(json_path: &Path, key: &[u8]) -> io::Result<Self> {
+        let content = fs::read(json_path)?;
 
-        if parent.is_some() {
-            let parent = parent.unwrap();
-            if !parent.exists() {
-                let _ = fs::create_dir_all(parent);
+        match serde_json::from_slice::<EncryptedManifest>(&content) {
+            Ok(manifest) => manifest.decrypt(key),
+            Err(_) if !manifests_encrypted(json_path, key)? => Ok(serde_json::from_slice::<Snapshot>(&content)?),
+            Err(_) => {
+                let message = format!("Manifest {} is not encrypted.", json_path.display());
+                Err(io::Error::new(io::ErrorKind::InvalidData, message))
             }
         }
+    }
 
-        let mut file = fs::File::create(file_path)?;
-        let json = serde_json::to_string_pretty(&self)?;
-        file.write_all(json.as_bytes())?;
+    /// Content of the manifest of this snapshot: a header with the format version and the nonce,
+    /// and the snapshot encrypted and authenticated with `key`.
+    pub fn to_manifest(&self, key: &[u8]) -> io::Result<Vec<u8>> {
+        let (ciphertext, nonce) = stream::encrypt_segmented(&serde_json::to_vec(&self)?, key);
+        let manifest = EncryptedManifest { version: MANIFEST_VERSION, nonce: hex::encode(nonce), ciphertext: hex::encode(ciphertext) };
+
+        Ok(serde_json::to_vec_pretty(&manifest)?)
+    }
+
+    /// Encrypt with `key`, through `journal`, the manifests of `snapshot_dir` written before manifests were encrypted.
+    pub fn encrypt_plaintext_manifests(snapshot_dir: &Path, key: &[u8], journal: &mut Journal) -> io::Result<()> {
+        if !snapshot_dir.exists() {
+            return Ok(());
+        }
+
+        for json_path in utils::list_json_snapshots(snapshot_dir)? {
+            let content = fs::read(&json_path)?;
+
+            if serde_json::from_slice::<EncryptedManifest>(&content).is_err() {
+                let snapshot = Self::from_json_to_snapshot(&json_path, key)?;
+                journal.write(&json_path, &snapshot.to_manifest(key)?)?;
+            }
+        }
 
         Ok(())
     }
+}
 
-    pub fn from_json_to_snapshot(json_path: &Path) -> io::Result<Self> {
-        let content = fs::read(json_path)?;
+impl EncryptedManifest {
+    /// Snapshot in this manifest, decrypted with `key`.
+    fn decrypt(&self, key: &[u8]) -> io::Result<Snapshot> {
+        if self.version != MANIFEST_VERSION {
+            let message = format!("Unsupported manifest version {}.", self.version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid manifest: {err}"));
+        let nonce = hex::decode(&self.nonce).map_err(invalid)?
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid manifest: nonce is not 12 bytes long"))?;
+        let ciphertext = hex::decode(&self.ciphertext).map_err(invalid)?;
+
+        let mut json = Vec::new();
+        DecryptReader::new(ciphertext.as_slice(), key, nonce).read_to_end(&mut json)
+            .map_err(|err| io::Error::new(err.kind(), format!("Failed to decrypt manifest: {err}")))?;
+
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// Whether every manifest of the backup holding the manifest at `json_path` is encrypted: its key store says so
+/// under a MAC keyed from `key`, or one of its manifests is encrypted already.
+/// Backups without a key store predate encrypted manifests.
+fn manifests_encrypted(json_path: &Path, key: &[u8]) -> io::Result<bool> {
+    let Some(snapshot_dir) = json_path.parent() else {
+        return Ok(false);
+    };
+
+    if let Some(dest) = snapshot_dir.parent()
+        && KeyStore::load(dest)?.is_some_and(|store| store.manifest_version(key) >= MANIFEST_VERSION) {
+        return Ok(true);
+    }
+
+    // resetting the key store is not enough to pass a plaintext manifest off next to encrypted ones.
+    for manifest in utils::list_json_snapshots(snapshot_dir)? {
+        if serde_json::from_slice::<EncryptedManifest>(&fs::read(&manifest)?).is_ok() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+impl FileEntry {
+    /// Whether the entry is a regular file whose content is stored in a blob.
+    pub fn has_blob(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+
+    /// Whether this entry and `other` hold the same content: their plaintext digests when both have one,
+    /// the blob hashes for entries written before digests were recorded.
+    pub fn same_content(&self, other: &FileEntry) -> bool {
+        match (&self.digest, &other.digest) {
+            (Some(digest), Some(other_digest)) => digest == other_digest,
+            _ => self.hash == other.hash,
+        }
+    }
+
+    /// The blobs holding the content of this entry in order, none for entries other than regular files.
+    /// Entries written before files were chunked have a single blob holding the whole file.
+    pub fn blobs(&self) -> Vec<Chunk> {
+        match &self.chunks {
+            _ if !self.has_blob() => Vec::new(),
+            Some(chunks) => chunks.clone(),
+            None => vec![Chunk { hash: self.hash.clone(), nonce: self.nonce, size: self.size, segmented: false, naming: BlobNaming::Compressed }],
+        }
+    }
+
+    /// Stream the blobs of this entry from `blobs_dir` to `writer`, decrypted with `key` and decompressed with `engine`.
+    /// Returns the number of bytes of the original file written, memory use does not depend on the size of the file.
+    ///
+    /// Fails when a blob does not match its name, or the content does not have the recorded size or digest.
+    /// Since blobs are checked as they are streamed, `writer` may already hold part of the content then.
+    pub fn write_content(&self, blobs_dir: &Path, key: &[u8], engine: &dyn CompressionEngine, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut content = HashingWriter::new(writer);
+        let mut written = 0;
+
+        for chunk in self.blobs() {
+            written += chunk.write_content(blobs_dir, key, engine, &mut content)?;
+        }
+
+        // manifests written before sizes were recorded have a size of 0.
+        if self.size != 0 && written != self.size {
+            let message = format!("Blobs of {} decompressed to {written} bytes, expected {}.", self.hash, self.size);
+            return Err(SnapError::Verify(message));
+        }
+
+        match &self.digest {
+            Some(digest) if content.hex_digest() != *digest => {
+                Err(SnapError::Verify(format!("Content of {} does not match its digest.", self.hash)))
+            },
+            _ => Ok(written),
+        }
+    }
+
+    /// Read the whole content of this entry in memory, see `write_content`.
+    pub fn read_content(&self, blobs_dir: &Path, key: &[u8], engine: &dyn CompressionEngine) -> Result<Vec<u8>, SnapError> {
+        let mut content = Vec::with_capacity(self.size as usize);
+        self.write_content(blobs_dir, key, engine, &mut content)?;
+
+        Ok(content)
+    }
+}
+
+impl Chunk {
+    /// Reader over the decrypted blob of this chunk in `blobs_dir`, which still holds compressed content.
+    ///
+    /// Segmented blobs are decrypted and authenticated one segment at a time. Blobs written before encryption
+    /// was segmented are a single message, decrypted at once.
+    pub fn open(&self, blobs_dir: &Path, key: &[u8]) -> Result<Box<dyn Read>, SnapError> {
+        let mut blob = BufReader::new(fs::File::open(blobs_dir.join(&self.hash))?);
+
+        if self.segmented {
+            return Ok(Box::new(DecryptReader::new(blob, key, self.nonce)));
+        }
+
+        let mut ciphertext = Vec::new();
+        blob.read_to_end(&mut ciphertext)?;
+
+        match crypto::decrypt_file_bytes(&ciphertext, key, &self.nonce) {
+            Ok(decrypted) => Ok(Box::new(io::Cursor::new(decrypted))),
+            Err(err) => {
+                let message = "Failed to decrypt target file";
+                Err(SnapError::EncryptError(message.into(), err))
+            }
+        }
+    }
+
+    /// Stream the content of this chunk from `blobs_dir` to `writer`, decrypted with `key` and decompressed with `engine`,
+    /// and return its size. Fails with `SnapError::Verify` when the blob does not match its name.
+    pub fn write_content(&self, blobs_dir: &Path, key: &[u8], engine: &dyn CompressionEngine, writer: &mut dyn Write) -> Result<u64, SnapError> {
+        let mut blob = self.open(blobs_dir, key)?;
+
+        let (written, name) = match self.naming {
+            BlobNaming::Compressed => {
+                let mut reader = HashingReader::new(blob);
+                (decompress_blob(engine, &mut reader, writer)?, reader.hex_digest())
+            },
+            BlobNaming::Plaintext => {
+                let mut writer = HashingWriter::new(writer);
+                (decompress_blob(engine, &mut blob, &mut writer)?, writer.hex_digest())
+            },
+            BlobNaming::Keyed => {
+                let mut writer = HashingWriter::with_hasher(writer, crypto::blob_mac(key));
+                (decompress_blob(engine, &mut blob, &mut writer)?, writer.hex_digest())
+            },
+        };
+
+        if name != self.hash {
+            return Err(SnapError::Verify(format!("Blob {} does not match its hash.", self.hash)));
+        }
+
+        Ok(written)
+    }
+}
+
+/// struct `HashingReader` computes the sha256 of everything read through it.
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, hasher: Sha256::new() }
+    }
 
-        let data = serde_json::from_slice::<Snapshot>(&content)?;
+    /// Hex encoded sha256 of what was read so far.
+    pub fn hex_digest(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+
+        Ok(read)
+    }
+}
+
+/// struct `HashingWriter` computes the sha256, or the MAC given to `with_hasher`, of everything written through it.
+pub struct HashingWriter<W: Write, H = Sha256> {
+    inner: W,
+    hasher: H,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, hasher: Sha256::new() }
+    }
+}
 
-        Ok(data)
+impl<W: Write, H: sha2::digest::Update + sha2::digest::FixedOutput + Clone> HashingWriter<W, H> {
+    pub fn with_hasher(inner: W, hasher: H) -> Self {
+        Self { inner, hasher }
     }
+
+    /// Hex encoded digest of what was written so far.
+    pub fn hex_digest(&self) -> String {
+        hex::encode(sha2::digest::FixedOutput::finalize_fixed(self.hasher.clone()))
+    }
+}
+
+impl<W: Write, H: sha2::digest::Update> Write for HashingWriter<W, H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read the file at `path` one chunk of `chunk_size` bytes on average at a time and return its chunks, size and digest.
+///
+/// Every chunk is named after the MAC of its content keyed from `key`. Chunks in `known_blobs` are reused, the others are
+/// compressed with `engine` on their own, encrypted with `key` and written to `target` through `journal`.
+fn read_chunks(path: &Path, chunk_size: u32, engine: &dyn CompressionEngine, key: &[u8], target: &Path, known_blobs: &Mutex<HashMap<String, Chunk>>, journal: &Mutex<&mut Journal>) -> Result<(Vec<Chunk>, u64, String), SnapError> {
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    let mut digest = Sha256::new();
+    let blob_mac = crypto::blob_mac(key);
+
+    for data in chunker::stream(fs::File::open(path)?, chunk_size) {
+        let data = data?;
+        let hash = hex::encode(blob_mac.clone().chain_update(&data).finalize().into_bytes());
+        digest.update(&data);
+
+        let known = known_blobs.lock().unwrap().get(&hash).cloned();
+        let stored = match known {
+            Some(chunk) => chunk,
+            None => {
+                let (ciphertext, nonce) = stream::encrypt_segmented(&engine.compress(&data)?, key);
+                let chunk = Chunk { hash: hash.clone(), nonce, size: 0, segmented: true, naming: BlobNaming::Keyed };
+
+                // another worker may have stored the same chunk in the meantime, its blob and nonce win.
+                let mut known_blobs = known_blobs.lock().unwrap();
+                match known_blobs.get(&hash) {
+                    Some(chunk) => chunk.clone(),
+                    None => {
+                        journal.lock().unwrap().write(&target.join(&hash), &ciphertext)?;
+                        known_blobs.insert(hash, chunk.clone());
+                        chunk
+                    }
+                }
+            }
+        };
+
+        size += data.len() as u64;
+        chunks.push(Chunk { size: data.len() as u64, ..stored });
+    }
+
+    Ok((chunks, size, format!("{:x}", digest.finalize())))
+}
+
+/// Decompress `blob` with `engine` to `writer` and return the number of bytes written.
+fn decompress_blob(engine: &dyn CompressionEngine, blob: &mut dyn Read, writer: &mut dyn Write) -> Result<u64, SnapError> {
+    let written = engine.decompress_stream(blob, writer)?;
+    // decompressors may stop before the end of the blob, which still has to be hashed and authenticated.
+    io::copy(blob, &mut io::sink())?;
+
+    Ok(written)
+}
+
+fn serialize_sorted<S: Serializer>(files: &HashMap<PathBuf, FileEntry>, serializer: S) -> Result<S::Ok, S::Error> {
+    files.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+/// Whether `entry` of the last snapshot recorded `metadata`, entries written before metadata was recorded never did.
+fn metadata_unchanged(entry: &FileEntry, metadata: &FileMetadata) -> bool {
+    entry.metadata.as_ref().is_some_and(|recorded| recorded.same_as(metadata))
+}
+
+/// Hash identifying the content of a file split into the chunks named `chunk_hashes`, in order.
+pub fn chunk_list_hash<'a>(chunk_hashes: impl Iterator<Item = &'a str>) -> String {
+    let mut hasher = Sha256::new();
+
+    for hash in chunk_hashes {
+        hasher.update(hash.as_bytes());
+    }
+
+    format!("{:x}", hasher.finalize())
 }
 
 
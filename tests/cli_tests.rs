@@ -1,10 +1,12 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use assert_cmd::Command;
-use predicates::str::contains;
+use predicates::{prelude::PredicateBooleanExt, str::contains};
+use snapsafe::{crypto::{self, keyslot::KeyStore, password::{Password, PasswordPolicy}}, utils::{self, gc::GarbageCollector, journal::Journal, rules::BackupRules, snapshot::Snapshot}};
 
 mod common;
 use common::{compare_dirs, get_password, get_test_registry, setup_file_dirs, setup_dir, write_test_file, clear_test_registry};
+use sha2::Digest;
 use tempfile::tempdir;
 
 use crate::common::copy_dir_contents;
@@ -33,6 +35,49 @@ fn backup_n_times(n: usize, source: PathBuf, dest: PathBuf, registry: String) ->
     (source, dest)
 }
 
+/// Unlock the key of the backup at `dest` with the test password.
+fn unlock_test_key(dest: &Path) -> [u8; 32] {
+    KeyStore::load(dest).unwrap().unwrap().unlock(&get_password()).unwrap().1
+}
+
+/// The first manifest of the backup at `dest`, decrypted and serialized back to json.
+fn first_manifest(dest: &Path) -> String {
+    let manifest = std::fs::read_dir(dest.join("snapshot")).unwrap().next().unwrap().unwrap().path();
+    let snapshot = Snapshot::from_json_to_snapshot(&manifest, &unlock_test_key(dest)).unwrap();
+
+    serde_json::to_string(&snapshot).unwrap()
+}
+
+/// Turn the backup of `source` at `dest` into one made before key slots: blobs and a plaintext manifest written
+/// with a key derived from the password and a key salt, and the password kept in the registry.
+fn make_pre_key_slot_backup(source: &Path, dest: &Path, registry: &str) {
+    let registry_json = Path::new(registry).join("snapsafe_test_registry.json");
+    let mut entries: serde_json::Value = serde_json::from_slice(&std::fs::read(&registry_json).unwrap()).unwrap();
+    let algorithm = entries["registry"][0]["compression_algorithm"].as_str().map(String::from);
+
+    for dir in ["blobs", "snapshot"] {
+        std::fs::remove_dir_all(dest.join(dir)).unwrap();
+        std::fs::create_dir(dest.join(dir)).unwrap();
+    }
+    std::fs::remove_file(KeyStore::path(dest)).unwrap();
+    std::fs::write(dest.join("key_salt"), [7u8; 16]).unwrap();
+
+    let key = crypto::derive_key(&get_password(), &[7u8; 16]);
+    let engine = utils::generate_compression_engine(algorithm).unwrap().0;
+    let mut gc = GarbageCollector::new(dest.join("blobs"), 3);
+    let mut journal = Journal::begin(dest, "backup").unwrap();
+    let snapshot = Snapshot::create(source, &dest.join("blobs"), &key, None, engine, &BackupRules::new(vec![], vec![], false, false), &mut journal).unwrap();
+    snapshot.save(&dest.join("snapshot"), &key, &mut gc, &mut journal).unwrap();
+    journal.commit().unwrap();
+
+    let manifest = std::fs::read_dir(dest.join("snapshot")).unwrap().next().unwrap().unwrap().path();
+    std::fs::write(&manifest, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+    let password = Password::new(get_password(), &PasswordPolicy::default()).unwrap();
+    entries["registry"][0]["password"] = serde_json::to_value(password).unwrap();
+    std::fs::write(&registry_json, serde_json::to_vec(&entries).unwrap()).unwrap();
+}
+
 // BACKUP COMMAND TESTS
 
 #[test]
@@ -448,7 +493,7 @@ fn test_cli_list_after_backup_and_delete_should_print_nothing() {
 }
 
 #[test]
-fn test_cli_list_after_backup_and_restore_should_print_nothing() {
+fn test_cli_list_after_backup_and_restore_should_keep_snapshot() {
     let registry = get_test_registry();
     clear_test_registry(&registry);
 
@@ -477,6 +522,1616 @@ fn test_cli_list_after_backup_and_restore_should_print_nothing() {
 
     let assert = cmd2.assert();
 
+    clear_test_registry(&registry);
+    assert.success().stdout(contains("Snapshots: 1"));
+}
+
+#[test]
+fn test_cli_list_after_backup_and_restore_with_pop_should_print_nothing() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let restore_dest = tempdir().unwrap();
+    let restore_dest = restore_dest.path();
+
+    let (source, dest) = setup_file_dirs();
+    let (_, _) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(restore_dest)
+        .arg("--pop");
+
+    cmd.assert().success();
+
+    let mut cmd2 = Command::cargo_bin("snapsafe").unwrap();
+    cmd2.env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry).arg("list");
+
+    let assert = cmd2.assert();
+
     clear_test_registry(&registry);
     assert.success().stdout(contains("No data has been backed up"));
 }
+
+#[test]
+fn test_cli_restore_with_pop_removes_blobs_only_that_snapshot_uses() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let restore_dest = tempdir().unwrap();
+    let restore_dest = restore_dest.path();
+
+    let (source, dest) = setup_file_dirs();
+    let (source, dest) = backup_n_times(1, source, dest, registry.clone());
+    let blobs_before = std::fs::read_dir(dest.join("blobs")).unwrap().count();
+
+    write_test_file(source.join("file_1.txt"), "Only in the second snapshot");
+    let (_, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(restore_dest)
+        .arg("--pop");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+
+    // the file added by the second backup only lives in the popped snapshot.
+    assert!(restore_dest.join("file_1.txt").exists());
+    assert_eq!(std::fs::read_dir(dest.join("snapshot")).unwrap().count(), 1);
+    assert_eq!(std::fs::read_dir(dest.join("blobs")).unwrap().count(), blobs_before);
+    assert!(!dest.join("journal.json").exists());
+}
+
+#[test]
+fn test_cli_restore_same_snapshot_twice_should_succeed() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (source, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    for _ in 0..2 {
+        let restore_dest = setup_dir();
+
+        let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+        cmd.env("SNAPSAFE_PASSWORD", get_password())
+            .env("SNAPSAFE_TEST_REGISTRY", &registry)
+            .env("TEST_CONFIG", &registry)
+            .arg("restore")
+            .arg("--origin")
+            .arg(&dest)
+            .arg("--output")
+            .arg(&restore_dest);
+
+        cmd.assert().success();
+        assert!(compare_dirs(source.clone(), restore_dest).unwrap());
+    }
+
+    clear_test_registry(&registry);
+}
+
+// DIFF COMMAND TESTS
+
+#[test]
+fn test_cli_diff_after_two_backups_reports_added_file() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(2, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("diff")
+        .arg("--origin")
+        .arg(&dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("+ file_1.txt"))
+        .stdout(contains("Summary: 1 added, 0 removed, 0 modified, 3 unchanged"));
+}
+
+#[test]
+fn test_cli_diff_with_only_one_snapshot_should_fail() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("diff")
+        .arg("--origin")
+        .arg(&dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure()
+        .stderr(contains("Snapshot latest~1 does not exist"));
+}
+
+#[test]
+fn test_cli_diff_selects_snapshots_by_time() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(2, source.clone(), dest.clone(), registry.clone());
+
+    let later = (chrono::Utc::now() + chrono::Duration::minutes(1)).to_rfc3339();
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("diff")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--to-at")
+        .arg(&later);
+
+    let later = cmd.assert();
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("diff")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--from-at")
+        .arg("1999-01-01");
+
+    let earlier = cmd.assert();
+
+    clear_test_registry(&registry);
+    later.success().stdout(contains("+ file_1.txt"));
+    earlier.failure().stderr(contains("Snapshot at 1999-01-01 23:59:59 does not exist"));
+}
+
+#[test]
+fn test_cli_diff_rejects_snapshot_ids_that_are_paths() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(2, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("diff")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--from")
+        .arg("../keys");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("Invalid snapshot selector '../keys'"));
+}
+
+#[test]
+fn test_cli_diff_file_prints_unified_diff_of_changed_lines() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (source, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    write_test_file(source.join("file1.txt"), "This is the new content of file1");
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("diff")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--file")
+        .arg("file1.txt");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("-This is the content of file1"))
+        .stdout(contains("+This is the new content of file1"));
+}
+
+#[test]
+fn test_cli_diff_file_accepts_path_relative_to_current_dir() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (source, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    write_test_file(source.join("file1.txt"), "This is the new content of file1");
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("diff")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--file")
+        .arg("./file1.txt");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("+++ file1.txt ("))
+        .stdout(contains("+This is the new content of file1"));
+}
+
+// KEY COMMAND TESTS
+
+#[test]
+fn test_cli_key_change_unlocks_backup_with_new_password_only() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    let (source, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+    let new_password = "NewValidP4$";
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_NEW_PASSWORD", new_password)
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("key")
+        .arg("change")
+        .arg("--origin")
+        .arg(&dest);
+
+    cmd.assert().success().stdout(contains("Password of key slot 1 changed."));
+
+    let mut cmd2 = Command::cargo_bin("snapsafe").unwrap();
+    cmd2.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    cmd2.assert().failure().stderr(contains("Password Error: IncorrectPassword"));
+
+    let mut cmd3 = Command::cargo_bin("snapsafe").unwrap();
+    cmd3.env("SNAPSAFE_PASSWORD", new_password)
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = cmd3.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+    assert!(compare_dirs(source, restore_dest).unwrap());
+}
+
+#[test]
+fn test_cli_key_remove_refuses_last_slot() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("key")
+        .arg("remove")
+        .arg("--origin")
+        .arg(&dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure()
+        .stderr(contains("Cannot remove the last key slot"));
+}
+
+// VERIFY COMMAND TESTS
+
+#[test]
+fn test_cli_verify_after_backups_reports_every_file_ok() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(2, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("verify")
+        .arg("--origin")
+        .arg(&dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("[OK] file1.txt"))
+        .stdout(contains("Verified 5 files in 2 snapshots: 5 ok, 0 failed"));
+}
+
+#[test]
+fn test_cli_verify_with_missing_blob_should_fail() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let blob = std::fs::read_dir(dest.join("blobs")).unwrap().next().unwrap().unwrap().path();
+    std::fs::remove_file(blob).unwrap();
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("verify")
+        .arg("--origin")
+        .arg(&dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure()
+        .stdout(contains("[MISSING]"))
+        .stderr(contains("1 of 2 files failed verification"));
+}
+
+// SNAPSHOTS COMMAND TESTS
+
+#[test]
+fn test_cli_snapshots_after_two_backups_lists_both() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(2, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("snapshots")
+        .arg("--origin")
+        .arg(&dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("Number: 2\n Created:"))
+        .stdout(contains("Files: 2 (2 updated)\n Size: 59 bytes"))
+        .stdout(contains("Files: 3 (1 updated)\n Size: 90 bytes"));
+}
+
+// LS COMMAND TESTS
+
+#[test]
+fn test_cli_ls_recursive_long_shows_files_of_snapshot() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("ls")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--recursive")
+        .arg("--long");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("logs/"))
+        .stdout(contains("  file2.log"))
+        .stdout(contains("29  "));
+}
+
+// CONFIG COMMAND TESTS
+
+#[test]
+fn test_cli_config_reports_rejected_chunk_size() {
+    let home = tempdir().unwrap();
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("HOME", home.path())
+        .arg("config")
+        .arg("--global")
+        .write_stdin("d\ngzip\n3\n12\n");
+
+    cmd.assert().success()
+        .stdout(contains("Default is 1048576"))
+        .stdout(contains("Invalid chunk size 12, expected between"))
+        .stdout(contains("Average chunk size is set to 1048576 bytes"));
+}
+
+// CAT COMMAND TESTS
+
+#[test]
+fn test_cli_cat_writes_only_file_content_to_stdout() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("cat")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("logs/file2.log");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout("This is a log in file2. What?\n");
+}
+
+#[test]
+fn test_cli_cat_with_unknown_snapshot_should_fail() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("cat")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--snapshot")
+        .arg("1999-01-01T00-00-00-000000")
+        .arg("file1.txt");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure()
+        .stderr(contains("Snapshot 1999-01-01T00-00-00-000000 does not exist."));
+}
+
+#[test]
+fn test_cli_restore_with_path_only_restores_that_directory() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest)
+        .arg("logs");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success().stdout(contains("Restored 1 of 2 files."));
+    assert!(restore_dest.join("logs").join("file2.log").exists());
+    assert!(!restore_dest.join("file1.txt").exists());
+}
+
+#[test]
+fn test_cli_restore_with_exclude_matching_everything_should_fail() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest)
+        .arg("--exclude")
+        .arg("*");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("No file in the snapshot matches"));
+}
+
+#[test]
+fn test_cli_restore_with_latest_selector_restores_older_snapshot() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    let (_, dest) = backup_n_times(2, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--snapshot")
+        .arg("latest~1")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+    assert!(restore_dest.join("file1.txt").exists());
+    assert!(!restore_dest.join("file_1.txt").exists());
+}
+
+#[test]
+fn test_cli_ls_at_time_before_first_snapshot_should_fail() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("ls")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--at")
+        .arg("2000-01-01");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("Snapshot at 2000-01-01 23:59:59 does not exist."));
+}
+
+#[test]
+fn test_cli_delete_with_number_zero_is_rejected() {
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.arg("delete")
+        .arg("--number")
+        .arg("0")
+        .arg("--origin")
+        .arg("/tmp")
+        .arg("--force");
+
+    cmd.assert().failure().stderr(contains("invalid value '0'"));
+}
+
+#[test]
+fn test_cli_restore_on_conflict_rename_keeps_existing_file() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    std::fs::create_dir_all(&restore_dest).unwrap();
+    write_test_file(restore_dest.join("file1.txt"), "local edits");
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest)
+        .arg("--on-conflict")
+        .arg("rename");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("[renamed to"))
+        .stdout(contains("Summary: 1 written, 0 overwritten, 0 skipped, 0 kept newer, 1 renamed"));
+
+    let existing = std::fs::read_to_string(restore_dest.join("file1.txt")).unwrap();
+    assert_eq!(existing.trim(), "local edits");
+}
+
+#[test]
+fn test_cli_restore_on_conflict_fail_writes_nothing() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    std::fs::create_dir_all(&restore_dest).unwrap();
+    write_test_file(restore_dest.join("file1.txt"), "local edits");
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest)
+        .arg("--on-conflict")
+        .arg("fail");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("1 files already exist in the output directory: file1.txt"));
+    assert!(!restore_dest.join("logs").exists());
+}
+
+#[test]
+fn test_cli_restore_over_a_directory_in_place_of_a_file_moves_nothing() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let parent = tempdir().unwrap();
+    let restore_dest = parent.path().join("restored");
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    // the directory cannot be overwritten by the file of the snapshot at the same path.
+    std::fs::create_dir_all(restore_dest.join("file1.txt")).unwrap();
+    write_test_file(restore_dest.join("file1.txt/kept.txt"), "local file");
+
+    let before = tempdir().unwrap();
+    copy_dir_contents(&restore_dest, before.path()).unwrap();
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("1 files cannot replace what is in the output directory: file1.txt."));
+
+    assert!(compare_dirs(before.path().to_path_buf(), restore_dest).unwrap());
+    assert!(std::fs::read_dir(parent.path()).unwrap().any(|entry| entry.unwrap().file_name().to_string_lossy().ends_with(".failed")));
+}
+
+#[test]
+fn test_cli_restore_with_corrupted_blob_leaves_output_untouched_and_marks_failed_dir() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let blob = std::fs::read_dir(dest.join("blobs")).unwrap().next().unwrap().unwrap().path();
+    std::fs::write(blob, b"not a valid blob").unwrap();
+
+    let parent = tempdir().unwrap();
+    let restore_dest = parent.path().join("restored");
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("Partially restored files were left in"));
+    assert!(!restore_dest.exists());
+
+    let failed_dirs: Vec<_> = std::fs::read_dir(parent.path()).unwrap()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".failed"))
+        .collect();
+    assert_eq!(failed_dirs.len(), 1);
+}
+
+#[test]
+fn test_cli_backup_rolls_back_interrupted_backup_first() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (source, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    // leftovers of a backup that died before committing.
+    let pending_blob = dest.join("blobs").join("deadbeef.pending");
+    std::fs::write(&pending_blob, b"partial").unwrap();
+    std::fs::write(
+        dest.join("journal.json"),
+        r#"{"operation":"backup","started":"2024-05-01T10:00:00Z","committed":false,"writes":[],"removals":[]}"#,
+    ).unwrap();
+    write_test_file(source.join("file3.txt"), "A file added after the crash");
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("backup")
+        .arg("--source")
+        .arg(&source)
+        .arg("--dest")
+        .arg(&dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("Rolled back the interrupted backup started at 2024-05-01 10:00:00."))
+        .stdout(contains("Backup completed successfully"));
+    assert!(!pending_blob.exists());
+    assert!(!dest.join("journal.json").exists());
+}
+
+#[test]
+fn test_cli_backup_dry_run_writes_nothing() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (source, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+    write_test_file(source.join("file3.txt"), "Not backed up yet");
+
+    let snapshots_before = std::fs::read_dir(dest.join("snapshot")).unwrap().count();
+    let blobs_before = std::fs::read_dir(dest.join("blobs")).unwrap().count();
+
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("backup")
+        .arg("--source")
+        .arg(&source)
+        .arg("--dest")
+        .arg(&dest)
+        .arg("--dry-run");
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("Would store 1 files as new blobs"))
+        .stdout(contains("  + file3.txt"))
+        .stdout(contains("Would reuse 2 unchanged files"));
+
+    assert_eq!(std::fs::read_dir(dest.join("snapshot")).unwrap().count(), snapshots_before);
+    assert_eq!(std::fs::read_dir(dest.join("blobs")).unwrap().count(), blobs_before);
+}
+
+#[test]
+fn test_cli_dry_runs_leave_backup_made_before_key_slots_untouched() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (source, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    make_pre_key_slot_backup(&source, &dest, &registry);
+
+    write_test_file(source.join("file3.txt"), "Not backed up yet");
+
+    let before = tempdir().unwrap();
+    copy_dir_contents(&dest, before.path()).unwrap();
+
+    let mut backup = Command::cargo_bin("snapsafe").unwrap();
+    backup.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("backup")
+        .arg("--source")
+        .arg(&source)
+        .arg("--dest")
+        .arg(&dest)
+        .arg("--dry-run");
+
+    let backup = backup.assert();
+
+    let mut delete = Command::cargo_bin("snapsafe").unwrap();
+    delete.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("delete")
+        .arg("--snapshot")
+        .arg("latest")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--dry-run");
+
+    let delete = delete.assert();
+
+    clear_test_registry(&registry);
+    backup.success().stdout(contains("  + file3.txt"));
+    delete.success();
+
+    assert!(!KeyStore::exists(&dest));
+    assert!(compare_dirs(before.path().to_path_buf(), dest).unwrap());
+}
+
+#[test]
+fn test_cli_restore_leaves_backup_made_before_key_slots_untouched() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    let (source, dest) = backup_n_times(1, source, dest, registry.clone());
+    make_pre_key_slot_backup(&source, &dest, &registry);
+
+    let before = tempdir().unwrap();
+    copy_dir_contents(&dest, before.path()).unwrap();
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+
+    assert!(compare_dirs(source, restore_dest).unwrap());
+    assert!(!KeyStore::exists(&dest));
+    assert!(compare_dirs(before.path().to_path_buf(), dest).unwrap());
+}
+
+#[test]
+fn test_cli_delete_older_snapshot_keeps_blobs_of_newer_one() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    let (_, dest) = backup_n_times(2, source.clone(), dest.clone(), registry.clone());
+
+    let mut dry_run = Command::cargo_bin("snapsafe").unwrap();
+    dry_run.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("delete")
+        .arg("--snapshot")
+        .arg("latest~1")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--dry-run");
+
+    dry_run.assert().success()
+        .stdout(contains("Would remove 0 blobs"))
+        .stdout(contains("Would keep 2 blobs still referenced by other snapshots"));
+    assert_eq!(std::fs::read_dir(dest.join("snapshot")).unwrap().count(), 2);
+
+    let mut delete = Command::cargo_bin("snapsafe").unwrap();
+    delete.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("delete")
+        .arg("--snapshot")
+        .arg("latest~1")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--force");
+
+    delete.assert().success();
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+    assert!(compare_dirs(source, restore_dest).unwrap());
+}
+
+#[test]
+fn test_cli_backup_skips_excluded_files_and_records_rules() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    write_test_file(source.join(".snapsafeignore"), "logs/");
+    write_test_file(source.join("secret.key"), "do not back me up");
+    std::fs::create_dir_all(source.join("cache")).unwrap();
+    write_test_file(source.join("cache").join("CACHEDIR.TAG"), "Signature: 8a477f597d28d172789f06886806bc55");
+    write_test_file(source.join("cache").join("data.bin"), "cached");
+
+    let mut backup = Command::cargo_bin("snapsafe").unwrap();
+    backup.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("backup")
+        .arg("--source")
+        .arg(&source)
+        .arg("--dest")
+        .arg(&dest)
+        .arg("--exclude")
+        .arg("*.key");
+
+    backup.assert().success();
+
+    let mut ls = Command::cargo_bin("snapsafe").unwrap();
+    ls.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("ls")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--recursive");
+
+    let assert = ls.assert();
+
+    clear_test_registry(&registry);
+    assert.success()
+        .stdout(contains("file1.txt"))
+        .stdout(contains(".snapsafeignore"))
+        .stdout(contains("file2.log").not())
+        .stdout(contains("secret.key").not())
+        .stdout(contains("data.bin").not());
+
+    let manifest: serde_json::Value = serde_json::from_str(&first_manifest(&dest)).unwrap();
+
+    assert_eq!(manifest["rules"]["exclude"], serde_json::json!(["*.key"]));
+    assert_eq!(manifest["rules"]["exclude_caches"], serde_json::json!(true));
+    assert_eq!(manifest["rules"]["ignore_files"][".snapsafeignore"], serde_json::json!(["logs/"]));
+}
+
+#[test]
+fn test_cli_restore_keeps_mode_and_modified_time() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+
+    let script = source.join("run.sh");
+    write_test_file(&script, "#!/bin/sh\necho hello");
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+    std::fs::File::open(&script).unwrap().set_modified(modified).unwrap();
+
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest)
+        .arg("--no-owner");
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+
+    let restored = std::fs::metadata(restore_dest.join("run.sh")).unwrap();
+    assert_eq!(restored.permissions().mode() & 0o7777, 0o755);
+    assert_eq!(restored.modified().unwrap(), modified);
+}
+
+#[test]
+fn test_cli_backup_after_chmod_only_records_new_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+
+    let (source, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    // only the mode changes, the content stays the same.
+    std::fs::set_permissions(source.join("file1.txt"), std::fs::Permissions::from_mode(0o600)).unwrap();
+    let (_, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest)
+        .arg("--no-owner");
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+
+    assert_eq!(std::fs::read_dir(dest.join("snapshot")).unwrap().count(), 2);
+    let restored = std::fs::metadata(restore_dest.join("file1.txt")).unwrap();
+    assert_eq!(restored.permissions().mode() & 0o7777, 0o600);
+}
+
+#[test]
+fn test_cli_restore_recreates_symlinks_empty_dirs_and_fifos() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+
+    std::os::unix::fs::symlink("file1.txt", source.join("link.txt")).unwrap();
+    std::fs::create_dir_all(source.join("empty")).unwrap();
+    nix::unistd::mkfifo(&source.join("pipe"), nix::sys::stat::Mode::from_bits_truncate(0o644)).unwrap();
+
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+
+    assert_eq!(std::fs::read_link(restore_dest.join("link.txt")).unwrap(), PathBuf::from("file1.txt"));
+    assert!(restore_dest.join("empty").is_dir());
+    assert!(std::fs::symlink_metadata(restore_dest.join("pipe")).unwrap().file_type().is_fifo());
+}
+
+#[test]
+fn test_cli_backup_follow_symlinks_stores_link_targets() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+
+    std::os::unix::fs::symlink("file1.txt", source.join("link.txt")).unwrap();
+
+    let mut backup = Command::cargo_bin("snapsafe").unwrap();
+    backup.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("backup")
+        .arg("--source")
+        .arg(&source)
+        .arg("--dest")
+        .arg(&dest)
+        .arg("--follow-symlinks");
+
+    backup.assert().success();
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+
+    let restored = restore_dest.join("link.txt");
+    assert!(!std::fs::symlink_metadata(&restored).unwrap().file_type().is_symlink());
+    assert_eq!(std::fs::read(restored).unwrap(), std::fs::read(source.join("file1.txt")).unwrap());
+}
+
+#[test]
+fn test_cli_restore_recreates_hard_links() {
+    use std::os::unix::fs::MetadataExt;
+
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+
+    std::fs::hard_link(source.join("file1.txt"), source.join("logs").join("same_as_file1.txt")).unwrap();
+
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+
+    let manifest: serde_json::Value = serde_json::from_str(&first_manifest(&dest)).unwrap();
+    assert_eq!(manifest["files"]["logs/same_as_file1.txt"]["hard_link"], serde_json::json!("file1.txt"));
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+
+    let first = std::fs::metadata(restore_dest.join("file1.txt")).unwrap();
+    let link = std::fs::metadata(restore_dest.join("logs").join("same_as_file1.txt")).unwrap();
+    assert_eq!(first.ino(), link.ino());
+    assert_eq!(first.nlink(), 2);
+}
+
+#[test]
+fn test_cli_backup_stores_only_changed_chunks_of_large_file() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+
+    // pseudo random content, so the file is split into several chunks of the default size.
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut content: Vec<u8> = (0..4_000_000).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect();
+    std::fs::write(source.join("image.bin"), &content).unwrap();
+
+    let (source, dest) = backup_n_times(1, source, dest, registry.clone());
+    let blob_count = || std::fs::read_dir(dest.join("blobs")).unwrap().count();
+    let first_count = blob_count();
+
+    content[2_000_000..2_000_010].copy_from_slice(b"0123456789");
+    std::fs::write(source.join("image.bin"), &content).unwrap();
+
+    let (_, dest) = backup_n_times(1, source.clone(), dest.clone(), registry.clone());
+    let added = blob_count() - first_count;
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+
+    assert!(first_count >= 4, "only {first_count} blobs for the first backup");
+    assert!(added <= 2, "{added} blobs added for a 10 byte change");
+    assert_eq!(std::fs::read(restore_dest.join("image.bin")).unwrap(), content);
+}
+
+#[test]
+fn test_cli_parallel_backup_and_restore_match_sequential_ones() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let parallel_dest = setup_dir();
+    let restore_dest = setup_dir();
+
+    std::fs::create_dir_all(source.join("many")).unwrap();
+    for i in 0..30 {
+        write_test_file(source.join("many").join(format!("file_{i:02}.txt")), &format!("content of file {i}\n").repeat(i * 100));
+    }
+
+    let backup = |dest: &PathBuf, jobs: &str| {
+        let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+        cmd.env("SNAPSAFE_PASSWORD", get_password())
+            .env("SNAPSAFE_TEST_REGISTRY", &registry)
+            .env("TEST_CONFIG", &registry)
+            .arg("backup")
+            .arg("--source")
+            .arg(&source)
+            .arg("--dest")
+            .arg(dest)
+            .arg("--jobs")
+            .arg(jobs)
+            .assert()
+            .success();
+
+        first_manifest(dest)
+    };
+
+    let sequential = backup(&dest, "1");
+    let parallel = backup(&parallel_dest, "8");
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&parallel_dest)
+        .arg("--output")
+        .arg(&restore_dest)
+        .arg("--jobs")
+        .arg("8");
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.success();
+    assert!(compare_dirs(source, restore_dest).unwrap());
+
+    // both manifests list the same contents, in path order. Blob names differ, each backup has its own key.
+    let digests = |manifest: &str| {
+        let manifest: serde_json::Value = serde_json::from_str(manifest).unwrap();
+        manifest["files"].as_object().unwrap().iter().map(|(path, entry)| (path.clone(), entry["digest"].clone())).collect::<Vec<_>>()
+    };
+    assert_eq!(digests(&sequential), digests(&parallel));
+
+    let positions: Vec<_> = (0..30).map(|i| parallel.find(&format!("\"many/file_{i:02}.txt\"")).unwrap()).collect();
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn test_cli_backup_detects_changes_that_keep_size_and_modification_time() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+
+    let file_path = source.join("notes.txt");
+    write_test_file(&file_path, "first version");
+    let modified = std::fs::metadata(&file_path).unwrap().modified().unwrap();
+
+    let backup = |force_rehash: bool| {
+        let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+        cmd.env("SNAPSAFE_PASSWORD", get_password())
+            .env("SNAPSAFE_TEST_REGISTRY", &registry)
+            .env("TEST_CONFIG", &registry)
+            .arg("backup")
+            .arg("--source")
+            .arg(&source)
+            .arg("--dest")
+            .arg(&dest);
+
+        if force_rehash {
+            cmd.arg("--force-rehash");
+        }
+
+        let assert = cmd.assert();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert
+    };
+
+    backup(false).success();
+
+    // the manifest records the modification time of the file, not the time of the backup.
+    let manifest: serde_json::Value = serde_json::from_str(&first_manifest(&dest)).unwrap();
+    let recorded = &manifest["files"]["notes.txt"]["modified"];
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).unwrap();
+    assert_eq!(recorded["secs_since_epoch"], since_epoch.as_secs());
+    assert_eq!(recorded["nanos_since_epoch"], since_epoch.subsec_nanos());
+
+    // same size and modification time, only the status change time tells the content changed.
+    write_test_file(&file_path, "other version");
+    std::fs::File::options().write(true).open(&file_path).unwrap().set_modified(modified).unwrap();
+
+    let changed = backup(false);
+    let unchanged = backup(false);
+    let rehashed = backup(true);
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    changed.success();
+    unchanged.failure().stderr(contains("No File changes"));
+    rehashed.failure().stderr(contains("No File changes"));
+    assert.success();
+    assert_eq!(std::fs::read_to_string(restore_dest.join("notes.txt")).unwrap(), "other version\n");
+}
+
+#[test]
+fn test_cli_backup_after_chmod_skips_the_file_next_time() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (source, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    std::fs::set_permissions(source.join("file1.txt"), std::fs::Permissions::from_mode(0o600)).unwrap();
+    let (source, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    // the change key recorded with the new mode matches the file again, nothing is left to back up.
+    let mut cmd = Command::cargo_bin("snapsafe").unwrap();
+    cmd.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("backup")
+        .arg("--source")
+        .arg(&source)
+        .arg("--dest")
+        .arg(&dest);
+
+    let assert = cmd.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("No File changes"));
+    assert_eq!(std::fs::read_dir(dest.join("snapshot")).unwrap().count(), 2);
+}
+
+#[test]
+fn test_cli_restore_checks_content_against_its_recorded_digest() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir();
+    write_test_file(source.join("notes.txt"), "some notes");
+
+    let (_, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    // the digest is the sha256 of the content itself, whatever the compression.
+    let key = unlock_test_key(&dest);
+    let manifest_path = std::fs::read_dir(dest.join("snapshot")).unwrap().next().unwrap().unwrap().path();
+    let mut snapshot = Snapshot::from_json_to_snapshot(&manifest_path, &key).unwrap();
+    let entry = snapshot.files.get_mut(Path::new("notes.txt")).unwrap();
+    assert_eq!(entry.digest, Some(format!("{:x}", sha2::Sha256::digest(b"some notes\n"))));
+
+    entry.digest = Some("0".repeat(64));
+    std::fs::write(&manifest_path, snapshot.to_manifest(&key).unwrap()).unwrap();
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("does not match its digest"));
+    assert!(!restore_dest.join("notes.txt").exists());
+}
+
+#[test]
+fn test_cli_restore_rejects_manifest_paths_outside_the_output_directory() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let restore_dest = setup_dir().join("output");
+    write_test_file(source.join("notes.txt"), "some notes");
+
+    let (_, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    let key = unlock_test_key(&dest);
+    let manifest_path = std::fs::read_dir(dest.join("snapshot")).unwrap().next().unwrap().unwrap().path();
+    let mut snapshot = Snapshot::from_json_to_snapshot(&manifest_path, &key).unwrap();
+    let entry = snapshot.files.remove(Path::new("notes.txt")).unwrap();
+    snapshot.files.insert(PathBuf::from("../escape.txt"), entry);
+    std::fs::write(&manifest_path, snapshot.to_manifest(&key).unwrap()).unwrap();
+
+    let mut restore = Command::cargo_bin("snapsafe").unwrap();
+    restore.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("restore")
+        .arg("--origin")
+        .arg(&dest)
+        .arg("--output")
+        .arg(&restore_dest);
+
+    let assert = restore.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("is not a path inside the output directory"));
+    assert!(!restore_dest.parent().unwrap().join("escape.txt").exists());
+    assert!(!restore_dest.exists());
+}
+
+#[test]
+fn test_cli_blob_names_do_not_reveal_file_content() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let other_dest = setup_dir();
+    write_test_file(source.join("known.txt"), "a file anyone could have");
+
+    let (source, dest) = backup_n_times(1, source, dest, registry.clone());
+    let (_, other_dest) = backup_n_times(1, source, other_dest, registry.clone());
+    clear_test_registry(&registry);
+
+    let blob_names = |dest: &PathBuf| {
+        std::fs::read_dir(dest.join("blobs")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<std::collections::BTreeSet<_>>()
+    };
+
+    // hashing the known content does not find its blob, and every backup has its own key, hence its own names.
+    let known = format!("{:x}", sha2::Sha256::digest(b"a file anyone could have\n"));
+    assert!(!blob_names(&dest).contains(&known));
+    assert!(blob_names(&dest).is_disjoint(&blob_names(&other_dest)));
+}
+
+#[test]
+fn test_cli_manifest_on_disk_does_not_reveal_paths() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    let mut ls = Command::cargo_bin("snapsafe").unwrap();
+    ls.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("ls")
+        .arg("--origin")
+        .arg(&dest);
+
+    let assert = ls.assert();
+
+    clear_test_registry(&registry);
+    assert.success().stdout(contains("file1.txt"));
+
+    // only the header is readable without the key.
+    let manifest = std::fs::read_dir(dest.join("snapshot")).unwrap().next().unwrap().unwrap().path();
+    let content = std::fs::read_to_string(manifest).unwrap();
+    let header: serde_json::Value = serde_json::from_str(&content).unwrap();
+
+    assert_eq!(header["version"], 1);
+    assert!(header.get("files").is_none());
+    assert!(!content.contains("file1.txt"));
+    assert!(first_manifest(&dest).contains("file1.txt"));
+}
+
+#[test]
+fn test_cli_forged_plaintext_manifest_is_rejected() {
+    let registry = get_test_registry();
+    clear_test_registry(&registry);
+
+    let (source, dest) = setup_file_dirs();
+    let (_, dest) = backup_n_times(1, source, dest, registry.clone());
+
+    // a manifest swapped for plain JSON must not be trusted once the backup encrypts its manifests.
+    let manifest = std::fs::read_dir(dest.join("snapshot")).unwrap().next().unwrap().unwrap().path();
+    std::fs::write(&manifest, first_manifest(&dest)).unwrap();
+
+    let mut ls = Command::cargo_bin("snapsafe").unwrap();
+    ls.env("SNAPSAFE_PASSWORD", get_password())
+        .env("SNAPSAFE_TEST_REGISTRY", &registry)
+        .env("TEST_CONFIG", &registry)
+        .arg("ls")
+        .arg("--origin")
+        .arg(&dest);
+
+    let assert = ls.assert();
+
+    clear_test_registry(&registry);
+    assert.failure().stderr(contains("is not encrypted"));
+}